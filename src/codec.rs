@@ -0,0 +1,145 @@
+use anyhow::{anyhow, Result};
+use thiserror::Error;
+
+#[derive(Error, Debug, Clone, Eq, PartialEq)]
+pub enum CodecErrorCode {
+    #[error("Truncated input, {0} more bytes expected at offset {1}")]
+    Truncated(usize, usize),
+    #[error("Invalid UTF-8 string at offset {0}")]
+    InvalidString(usize),
+}
+
+/// Little endian writer of the fixed width integers and length prefixed strings the log
+/// and snapshot files are made of.
+#[derive(Debug, Default)]
+pub(crate) struct Encoder {
+    buf: Vec<u8>,
+}
+
+impl Encoder {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn u8(&mut self, value: u8) -> &mut Self {
+        self.buf.push(value);
+        self
+    }
+
+    pub(crate) fn u32(&mut self, value: u32) -> &mut Self {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    pub(crate) fn u64(&mut self, value: u64) -> &mut Self {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    pub(crate) fn str(&mut self, value: &str) -> &mut Self {
+        self.bytes(value.as_bytes())
+    }
+
+    /// `value` prefixed by its length.
+    pub(crate) fn bytes(&mut self, value: &[u8]) -> &mut Self {
+        self.u32(value.len() as u32);
+        self.buf.extend_from_slice(value);
+        self
+    }
+
+    pub(crate) fn finish(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.buf)
+    }
+}
+
+/// Reader of what `Encoder` wrote, failing with `CodecErrorCode` on short input.
+#[derive(Debug)]
+pub(crate) struct Decoder<'a> {
+    buf: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Decoder<'a> {
+    pub(crate) fn new(buf: &'a [u8]) -> Self {
+        Self { buf, offset: 0 }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.offset == self.buf.len()
+    }
+
+    pub(crate) fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        let rest = self.buf.len() - self.offset;
+        if rest < len {
+            return Err(anyhow!(CodecErrorCode::Truncated(len - rest, self.offset)));
+        }
+        let bytes = &self.buf[self.offset..self.offset + len];
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn str(&mut self) -> Result<String> {
+        let len = self.u32()? as usize;
+        let offset = self.offset;
+        let bytes = self.bytes(len)?;
+        String::from_utf8(bytes.to_vec())
+            .map_err(|_| anyhow!(CodecErrorCode::InvalidString(offset)))
+    }
+}
+
+/// CRC-32 (IEEE) of `bytes`.
+pub(crate) fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0_u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::codec::{crc32, CodecErrorCode, Decoder, Encoder};
+
+    #[test]
+    pub fn test_round_trip() {
+        let bytes = Encoder::new()
+            .u8(7)
+            .u32(70_000)
+            .u64(u64::MAX)
+            .bytes(&[1, 2])
+            .str("seg/1")
+            .finish();
+        let mut decoder = Decoder::new(&bytes);
+        assert_eq!(7, decoder.u8().unwrap());
+        assert_eq!(70_000, decoder.u32().unwrap());
+        assert_eq!(u64::MAX, decoder.u64().unwrap());
+        assert_eq!(2, decoder.u32().unwrap());
+        assert_eq!(&[1, 2], decoder.bytes(2).unwrap());
+        assert_eq!("seg/1", decoder.str().unwrap());
+        assert!(decoder.is_empty());
+        assert!(matches!(
+            decoder.u32().unwrap_err().downcast_ref::<CodecErrorCode>(),
+            Some(CodecErrorCode::Truncated(4, _))
+        ));
+        assert_eq!(0xCBF4_3926, crc32(b"123456789"));
+    }
+}
@@ -1,33 +1,461 @@
+use crate::segment::ResourceId;
 use parking_lot::RwLock;
-use petgraph::graph::DiGraph;
+use petgraph::algo::tarjan_scc;
+use petgraph::stable_graph::{NodeIndex, StableDiGraph};
+use petgraph::visit::EdgeRef;
+use petgraph::Direction;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
 
-#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Hash)]
-pub struct LockNode<'a> {
-    op: &'a str,
-    rid: &'a str,
+/// One hop of a wait-for cycle: `op` is blocked on resource `rid`.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Hash)]
+pub struct LockNode {
+    pub op: String,
+    pub rid: ResourceId,
+}
+
+/// A wait-for cycle. `nodes[i].op` waits for `nodes[i + 1].op` (wrapping around) to
+/// release `nodes[i].rid`.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct DeadLock {
+    pub nodes: Vec<LockNode>,
+}
+
+impl DeadLock {
+    pub fn ops(&self) -> Vec<String> {
+        self.nodes.iter().map(|node| node.op.clone()).collect()
+    }
+
+    pub fn resources(&self) -> Vec<ResourceId> {
+        self.nodes.iter().map(|node| node.rid.clone()).collect()
+    }
+}
+
+impl fmt::Display for DeadLock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for node in self.nodes.iter() {
+            write!(f, "{} -[{}]-> ", node.op, node.rid)?;
+        }
+        match self.nodes.first() {
+            Some(node) => write!(f, "{}", node.op),
+            None => Ok(()),
+        }
+    }
+}
+
+/// What a `VictimPolicy` knows about an operation that is part of a cycle.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct VictimCandidate {
+    pub op: String,
+    /// Order in which operations first asked the lock table for a lock, higher is younger.
+    pub started: u64,
+    pub locks_held: usize,
+    /// Locks granted to the operation over its whole lifetime.
+    pub work_done: usize,
+}
+
+/// User supplied victim selection, returns the op id to abort.
+pub type VictimSelector = Arc<dyn Fn(&[VictimCandidate]) -> String + Send + Sync>;
+
+/// How to break a deadlock: the operation picked from the candidates of a cycle is
+/// aborted and loses every lock it holds.
+#[derive(Clone, Default)]
+pub enum VictimPolicy {
+    #[default]
+    Youngest,
+    FewestLocks,
+    LeastWork,
+    Custom(VictimSelector),
+}
+
+impl fmt::Debug for VictimPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VictimPolicy::Youngest => write!(f, "Youngest"),
+            VictimPolicy::FewestLocks => write!(f, "FewestLocks"),
+            VictimPolicy::LeastWork => write!(f, "LeastWork"),
+            VictimPolicy::Custom(_) => write!(f, "Custom"),
+        }
+    }
+}
+
+impl VictimPolicy {
+    /// Ties are broken in favour of the youngest operation.
+    pub fn select(&self, candidates: &[VictimCandidate]) -> Option<String> {
+        let youngest_first = |c: &VictimCandidate| std::cmp::Reverse(c.started);
+        let victim = match self {
+            VictimPolicy::Youngest => candidates.iter().max_by_key(|c| c.started),
+            VictimPolicy::FewestLocks => candidates
+                .iter()
+                .min_by_key(|c| (c.locks_held, youngest_first(c))),
+            VictimPolicy::LeastWork => candidates
+                .iter()
+                .min_by_key(|c| (c.work_done, youngest_first(c))),
+            VictimPolicy::Custom(select) => {
+                return if candidates.is_empty() {
+                    None
+                } else {
+                    Some(select(candidates))
+                };
+            }
+        };
+        victim.map(|c| c.op.clone())
+    }
+}
+
+#[derive(Debug, Default)]
+struct WaitForGraph {
+    graph: StableDiGraph<String, ResourceId>,
+    nodes: HashMap<String, NodeIndex>,
+}
+
+impl WaitForGraph {
+    fn node(&mut self, op: &str) -> NodeIndex {
+        if let Some(idx) = self.nodes.get(op) {
+            return *idx;
+        }
+        let idx = self.graph.add_node(op.to_string());
+        self.nodes.insert(op.to_string(), idx);
+        idx
+    }
+
+    fn edges(&self, op: &str, direction: Direction) -> Vec<(String, ResourceId)> {
+        match self.nodes.get(op) {
+            Some(idx) => self
+                .graph
+                .edges_directed(*idx, direction)
+                .map(|edge| {
+                    let other = match direction {
+                        Direction::Outgoing => edge.target(),
+                        Direction::Incoming => edge.source(),
+                    };
+                    (self.graph[other].clone(), edge.weight().clone())
+                })
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    /// Walk the strongly connected component from its first node until a node repeats,
+    /// only following edges that stay inside the component.
+    fn cycle_of(&self, scc: &[NodeIndex]) -> DeadLock {
+        let members = scc.iter().copied().collect::<HashSet<_>>();
+        let mut visited = HashMap::new();
+        let mut path: Vec<LockNode> = vec![];
+        let mut current = scc[0];
+        while !visited.contains_key(&current) {
+            visited.insert(current, path.len());
+            let edge = self
+                .graph
+                .edges_directed(current, Direction::Outgoing)
+                .find(|edge| members.contains(&edge.target()))
+                .expect("every node of a cycle has an outgoing edge inside it");
+            path.push(LockNode {
+                op: self.graph[current].clone(),
+                rid: edge.weight().clone(),
+            });
+            current = edge.target();
+        }
+        DeadLock {
+            nodes: path.split_off(visited[&current]),
+        }
+    }
 }
 
 /// If there are mutual references between nodes (incoming outgoing),
-/// or if there is a circle between nodes, there will be a deadlock
+/// or if there is a circle between nodes, there will be a deadlock.
+///
+/// Nodes are operation ids and an edge `a -> b` labelled with a resource id means `a` is
+/// queued on that resource behind `b`. The lock manager keeps the graph up to date.
+#[derive(Debug, Clone, Default)]
 pub struct DealLockDetector {
-    lock_graph: Arc<RwLock<DiGraph<String, String>>>,
+    lock_graph: Arc<RwLock<WaitForGraph>>,
 }
 
 impl DealLockDetector {
-    fn link_node() {
-        todo!()
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record that `waiter` is blocked on `rid` by `holder`.
+    pub fn link_node(&self, waiter: &str, holder: &str, rid: &ResourceId) {
+        self.link_nodes(waiter, &[holder.to_string()], rid);
+    }
+
+    /// Record that `waiter` is blocked on `rid` by every operation of `holders`.
+    pub fn link_nodes(&self, waiter: &str, holders: &[String], rid: &ResourceId) {
+        let lock_graph = &mut *self.lock_graph.write();
+        let from = lock_graph.node(waiter);
+        let mut linked = lock_graph
+            .graph
+            .edges_directed(from, Direction::Outgoing)
+            .filter(|edge| edge.weight() == rid)
+            .map(|edge| edge.target())
+            .collect::<HashSet<_>>();
+        for holder in holders.iter() {
+            let to = lock_graph.node(holder);
+            if linked.insert(to) {
+                lock_graph.graph.add_edge(from, to, rid.clone());
+            }
+        }
+    }
+
+    /// Forget every edge that makes `waiter` wait on `rid`, e.g. after it was granted.
+    /// Operations that never waited only cost a shared latch.
+    pub fn unlink_waiter(&self, waiter: &str, rid: &ResourceId) {
+        if !self.lock_graph.read().nodes.contains_key(waiter) {
+            return;
+        }
+        let lock_graph = &mut *self.lock_graph.write();
+        if let Some(idx) = lock_graph.nodes.get(waiter).copied() {
+            let edges = lock_graph
+                .graph
+                .edges_directed(idx, Direction::Outgoing)
+                .filter(|edge| edge.weight() == rid)
+                .map(|edge| edge.id())
+                .collect::<Vec<_>>();
+            for edge in edges {
+                lock_graph.graph.remove_edge(edge);
+            }
+        }
+    }
+
+    /// Forget that `waiter` waits on `holder` for `rid`, e.g. after `holder` weakened its
+    /// lock to a mode `waiter` is compatible with.
+    pub fn unlink_edge(&self, waiter: &str, holder: &str, rid: &ResourceId) {
+        let lock_graph = &mut *self.lock_graph.write();
+        let (from, to) = match (lock_graph.nodes.get(waiter), lock_graph.nodes.get(holder)) {
+            (Some(from), Some(to)) => (*from, *to),
+            _ => return,
+        };
+        let edges = lock_graph
+            .graph
+            .edges_directed(from, Direction::Outgoing)
+            .filter(|edge| edge.target() == to && edge.weight() == rid)
+            .map(|edge| edge.id())
+            .collect::<Vec<_>>();
+        for edge in edges {
+            lock_graph.graph.remove_edge(edge);
+        }
+    }
+
+    /// Forget every edge that makes someone wait on `holder` for `rid`, e.g. after
+    /// `holder` released it or left the queue.
+    pub fn unlink_holder(&self, holder: &str, rid: &ResourceId) {
+        if !self.lock_graph.read().nodes.contains_key(holder) {
+            return;
+        }
+        let lock_graph = &mut *self.lock_graph.write();
+        if let Some(idx) = lock_graph.nodes.get(holder).copied() {
+            let edges = lock_graph
+                .graph
+                .edges_directed(idx, Direction::Incoming)
+                .filter(|edge| edge.weight() == rid)
+                .map(|edge| edge.id())
+                .collect::<Vec<_>>();
+            for edge in edges {
+                lock_graph.graph.remove_edge(edge);
+            }
+        }
+    }
+
+    /// Drop the node of `op` once nobody waits for it and it waits for nobody.
+    pub fn remove_isolated(&self, op: &str) {
+        if !self.lock_graph.read().nodes.contains_key(op) {
+            return;
+        }
+        let lock_graph = &mut *self.lock_graph.write();
+        if let Some(idx) = lock_graph.nodes.get(op).copied() {
+            if lock_graph.graph.neighbors_undirected(idx).next().is_none() {
+                lock_graph.graph.remove_node(idx);
+                lock_graph.nodes.remove(op);
+            }
+        }
+    }
+
+    /// Drop the node of `op` with every edge from and to it, e.g. once it was aborted.
+    pub fn remove_node(&self, op: &str) {
+        let lock_graph = &mut *self.lock_graph.write();
+        if let Some(idx) = lock_graph.nodes.remove(op) {
+            lock_graph.graph.remove_node(idx);
+        }
     }
 
-    fn find_parent() -> String {
-        todo!()
+    /// The operations `op` is currently waiting for.
+    pub fn find_parent(&self, op: &str) -> Vec<String> {
+        let lock_graph = &*self.lock_graph.read();
+        let mut parents = lock_graph
+            .edges(op, Direction::Outgoing)
+            .into_iter()
+            .map(|(holder, _)| holder)
+            .collect::<Vec<_>>();
+        parents.sort();
+        parents.dedup();
+        parents
+    }
+
+    pub fn has_incoming(&self, op: &str) -> bool {
+        let lock_graph = &*self.lock_graph.read();
+        !lock_graph.edges(op, Direction::Incoming).is_empty()
+    }
+
+    pub fn has_outgoing(&self, op: &str) -> bool {
+        let lock_graph = &*self.lock_graph.read();
+        !lock_graph.edges(op, Direction::Outgoing).is_empty()
+    }
+
+    pub fn node_size(&self) -> usize {
+        self.lock_graph.read().nodes.len()
+    }
+
+    /// Run cycle detection over the current wait-for graph, one `DeadLock` per cycle.
+    pub fn detect(&self) -> Vec<DeadLock> {
+        let lock_graph = &*self.lock_graph.read();
+        tarjan_scc(&lock_graph.graph)
+            .into_iter()
+            .filter(|scc| scc.len() > 1 || lock_graph.graph.find_edge(scc[0], scc[0]).is_some())
+            .map(|scc| lock_graph.cycle_of(&scc))
+            .collect()
+    }
+
+    /// Run `detect` every `interval` on a background thread and hand the cycles found
+    /// to `on_dead_lock`. Detection stops when the returned handle is dropped.
+    pub fn spawn_periodic<F>(&self, interval: Duration, on_dead_lock: F) -> PeriodicDetection
+    where
+        F: Fn(Vec<DeadLock>) + Send + 'static,
+    {
+        let detector = self.clone();
+        PeriodicDetection::spawn(interval, move || {
+            let dead_locks = detector.detect();
+            if !dead_locks.is_empty() {
+                on_dead_lock(dead_locks);
+            }
+        })
+    }
+}
+
+/// Handle of a background thread running a detection task at a fixed interval.
+pub struct PeriodicDetection {
+    running: Arc<AtomicBool>,
+    join: Option<JoinHandle<()>>,
+}
+
+impl PeriodicDetection {
+    pub fn spawn<F>(interval: Duration, task: F) -> Self
+    where
+        F: Fn() + Send + 'static,
+    {
+        let running = Arc::new(AtomicBool::new(true));
+        let join = std::thread::spawn({
+            let running = running.clone();
+            move || {
+                while running.load(Ordering::Acquire) {
+                    std::thread::park_timeout(interval);
+                    if running.load(Ordering::Acquire) {
+                        task();
+                    }
+                }
+            }
+        });
+        Self {
+            running,
+            join: Some(join),
+        }
+    }
+
+    pub fn stop(&mut self) {
+        self.running.store(false, Ordering::Release);
+        if let Some(join) = self.join.take() {
+            join.thread().unpark();
+            let _ = join.join();
+        }
+    }
+}
+
+impl Drop for PeriodicDetection {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dead_lock_detector::{DealLockDetector, VictimCandidate, VictimPolicy};
+    use std::sync::mpsc::channel;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    pub fn test_detect_cycle() {
+        let detector = DealLockDetector::new();
+        detector.link_node("t1", "t2", &"r2".into());
+        detector.link_node("t2", "t3", &"r3".into());
+        assert!(detector.detect().is_empty());
+        assert!(detector.has_outgoing("t1"));
+        assert!(!detector.has_incoming("t1"));
+        assert_eq!(vec!["t2".to_string()], detector.find_parent("t1"));
+
+        detector.link_node("t3", "t1", &"r1".into());
+        let dead_locks = detector.detect();
+        assert_eq!(1, dead_locks.len());
+        let mut ops = dead_locks[0].ops();
+        ops.sort();
+        assert_eq!(vec!["t1", "t2", "t3"], ops);
+        let mut resources = dead_locks[0].resources();
+        resources.sort();
+        assert_eq!(vec!["r1", "r2", "r3"], resources);
+        println!("DeadLock = {}", dead_locks[0]);
+
+        detector.unlink_waiter("t3", &"r1".into());
+        assert!(detector.detect().is_empty());
+        detector.remove_isolated("t1");
+        assert_eq!(3, detector.node_size());
     }
 
-    fn has_incoming() -> bool {
-        todo!()
+    #[test]
+    pub fn test_periodic_detect() {
+        let detector = DealLockDetector::new();
+        detector.link_node("p1", "p2", &"r1".into());
+        detector.link_node("p2", "p1", &"r2".into());
+        let (tx, rx) = channel();
+        let mut periodic = detector.spawn_periodic(Duration::from_millis(5), move |dead_locks| {
+            let _ = tx.send(dead_locks);
+        });
+        let dead_locks = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(2, dead_locks[0].nodes.len());
+        periodic.stop();
     }
 
-    fn has_outgoing() -> bool {
-        todo!()
+    #[test]
+    pub fn test_victim_policy() {
+        let candidate = |op: &str, started, locks_held, work_done| VictimCandidate {
+            op: op.to_string(),
+            started,
+            locks_held,
+            work_done,
+        };
+        let candidates = vec![
+            candidate("old_busy", 1, 5, 9),
+            candidate("mid_idle", 2, 1, 4),
+            candidate("young_lazy", 3, 2, 1),
+        ];
+        let select = |policy: VictimPolicy| policy.select(&candidates).unwrap();
+        assert_eq!("young_lazy", select(VictimPolicy::Youngest));
+        assert_eq!("mid_idle", select(VictimPolicy::FewestLocks));
+        assert_eq!("young_lazy", select(VictimPolicy::LeastWork));
+        let oldest = VictimPolicy::Custom(Arc::new(|candidates: &[VictimCandidate]| {
+            candidates
+                .iter()
+                .min_by_key(|c| c.started)
+                .unwrap()
+                .op
+                .clone()
+        }));
+        assert_eq!("old_busy", select(oldest));
+        assert_eq!(None, VictimPolicy::Youngest.select(&[]));
     }
 }
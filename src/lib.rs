@@ -1,3 +1,4 @@
+mod codec;
 #[allow(dead_code)]
 pub mod dead_lock_detector;
 pub mod lock;
@@ -8,3 +9,9 @@ pub mod operation;
 pub mod operation_scheduler;
 #[allow(dead_code)]
 pub mod segment;
+pub mod segment_store;
+pub mod transaction;
+pub mod undo_log;
+pub mod value;
+mod wait_queue;
+pub mod wal;
@@ -8,6 +8,8 @@ lazy_static! {
         let mut op_lock_mapping = HashMap::new();
         op_lock_mapping.insert(OpType::Read, LockMode::Shared);
         op_lock_mapping.insert(OpType::Write, LockMode::Exclusive);
+        op_lock_mapping.insert(OpType::ReadForUpdate, LockMode::Update);
+        op_lock_mapping.insert(OpType::Increment, LockMode::Increment);
         op_lock_mapping.insert(OpType::NoOp, LockMode::NoLock);
         op_lock_mapping
     };
@@ -26,38 +28,136 @@ macro_rules! get_lock_mode {
     }};
 }
 
-#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq)]
+/// Rows and columns of the matrices below follow `LockMode::MODES`, a new mode adds its
+/// variant there and one row and column to each matrix.
+const MODE_COUNT: usize = 8;
+
+/// `COMPATIBILITY[held][require]`: whether `require` can be granted while another
+/// operation holds `held`.
+#[rustfmt::skip]
+const COMPATIBILITY: [[bool; MODE_COUNT]; MODE_COUNT] = [
+    //         S      X      IS     IX     SIX    U      I      NoLock
+    /* S   */ [true,  false, true,  false, false, true,  false, true],
+    /* X   */ [false, false, false, false, false, false, false, true],
+    /* IS  */ [true,  false, true,  true,  true,  true,  false, true],
+    /* IX  */ [false, false, true,  true,  false, false, false, true],
+    /* SIX */ [false, false, true,  false, false, false, false, true],
+    /* U   */ [true,  false, true,  false, false, false, false, true],
+    /* I   */ [false, false, false, false, false, false, true,  true],
+    /* NoL */ [true,  true,  true,  true,  true,  true,  true,  true],
+];
+
+/// `COVERS[held][other]`: whether holding `held` already grants everything `other` would.
+#[rustfmt::skip]
+const COVERS: [[bool; MODE_COUNT]; MODE_COUNT] = [
+    //         S      X      IS     IX     SIX    U      I      NoLock
+    /* S   */ [true,  false, true,  false, false, false, false, true],
+    /* X   */ [true,  true,  true,  true,  true,  true,  true,  true],
+    /* IS  */ [false, false, true,  false, false, false, false, true],
+    /* IX  */ [false, false, true,  true,  false, false, false, true],
+    /* SIX */ [true,  false, true,  true,  true,  false, false, true],
+    /* U   */ [true,  false, true,  false, false, true,  false, true],
+    /* I   */ [false, false, false, false, false, false, true,  true],
+    /* NoL */ [false, false, false, false, false, false, false, true],
+];
+
+#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq, Default)]
 pub enum LockMode {
     Shared,
     Exclusive,
+    /// Some descendant is (going to be) locked in `Shared` mode.
+    IntentionShared,
+    /// Some descendant is (going to be) locked in `Exclusive` mode.
+    IntentionExclusive,
+    /// The node is read as a whole and some descendants are going to be written.
+    SharedIntentionExclusive,
+    /// Read with the intention to write later, readers may join but no other updater,
+    /// so two readers can not deadlock upgrading to `Exclusive` together.
+    Update,
+    /// Commutative write, other increments may run concurrently.
+    Increment,
+    #[default]
     NoLock,
 }
 
 impl LockMode {
+    /// Every mode in declaration order, the index of a mode into the matrices.
+    pub const MODES: [LockMode; MODE_COUNT] = [
+        LockMode::Shared,
+        LockMode::Exclusive,
+        LockMode::IntentionShared,
+        LockMode::IntentionExclusive,
+        LockMode::SharedIntentionExclusive,
+        LockMode::Update,
+        LockMode::Increment,
+        LockMode::NoLock,
+    ];
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+
+    /// Whether `require_lock` can be granted while `self` is held by another operation.
+    ///
+    /// |     | IS | IX | S | SIX | U | I | X |
+    /// |-----|----|----|---|-----|---|---|---|
+    /// | IS  | y  | y  | y | y   | y | n | n |
+    /// | IX  | y  | y  | n | n   | n | n | n |
+    /// | S   | y  | n  | y | n   | y | n | n |
+    /// | SIX | y  | n  | n | n   | n | n | n |
+    /// | U   | y  | n  | y | n   | n | n | n |
+    /// | I   | n  | n  | n | n   | n | y | n |
+    /// | X   | n  | n  | n | n   | n | n | n |
     pub fn compatible(&self, require_lock: LockMode) -> bool {
-        use LockMode::*;
-        match *self {
-            Shared => require_lock == Shared,
-            Exclusive => false,
-            NoLock => true,
-        }
+        COMPATIBILITY[self.index()][require_lock.index()]
+    }
+
+    /// The mode of a granted group: the weakest mode covering every mode held, `NoLock`
+    /// for nobody. A request compatible with it is compatible with each holder.
+    pub fn group_mode(held_modes: impl IntoIterator<Item = LockMode>) -> LockMode {
+        held_modes
+            .into_iter()
+            .fold(LockMode::NoLock, |group, held| group.supremum(held))
     }
 
     pub fn upgradable(&self, require_lock: LockMode) -> bool {
+        *self != require_lock && require_lock.covers(*self)
+    }
+
+    /// Whether holding `self` already grants everything `other` would.
+    pub fn covers(&self, other: LockMode) -> bool {
+        COVERS[self.index()][other.index()]
+    }
+
+    /// The weakest mode covering both `self` and `other`, e.g. S + IX = SIX.
+    pub fn supremum(&self, other: LockMode) -> LockMode {
+        let strength = |mode: &LockMode| {
+            Self::MODES
+                .iter()
+                .filter(|weaker| mode.covers(**weaker))
+                .count()
+        };
+        Self::MODES
+            .iter()
+            .copied()
+            .filter(|mode| mode.covers(*self) && mode.covers(other))
+            .min_by_key(strength)
+            .unwrap_or(LockMode::Exclusive)
+    }
+
+    /// The intention mode an ancestor must hold before a descendant is locked in `self`.
+    pub fn intention(&self) -> LockMode {
         use LockMode::*;
         match *self {
-            Shared => require_lock == Exclusive,
-            _ => false,
+            Shared | IntentionShared => IntentionShared,
+            Exclusive | IntentionExclusive | SharedIntentionExclusive | Update | Increment => {
+                IntentionExclusive
+            }
+            NoLock => NoLock,
         }
     }
 }
 
-impl Default for LockMode {
-    fn default() -> Self {
-        LockMode::NoLock
-    }
-}
-
 #[derive(Debug, Clone, Default, Eq, PartialEq, Hash)]
 pub struct Lock {
     pub op_id: String,
@@ -74,3 +174,107 @@ impl Lock {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::lock::LockMode;
+    use crate::lock::LockMode::*;
+
+    #[test]
+    pub fn test_compatible_matrix() {
+        let modes = [
+            IntentionShared,
+            IntentionExclusive,
+            Shared,
+            SharedIntentionExclusive,
+            Exclusive,
+        ];
+        let matrix = [
+            [true, true, true, true, false],
+            [true, true, false, false, false],
+            [true, false, true, false, false],
+            [true, false, false, false, false],
+            [false, false, false, false, false],
+        ];
+        for (held_idx, held) in modes.iter().enumerate() {
+            for (require_idx, require) in modes.iter().enumerate() {
+                assert_eq!(
+                    matrix[held_idx][require_idx],
+                    held.compatible(*require),
+                    "{:?} {:?}",
+                    held,
+                    require
+                );
+                // The matrix is symmetric.
+                assert_eq!(held.compatible(*require), require.compatible(*held));
+            }
+            assert!(held.compatible(NoLock));
+        }
+    }
+
+    #[test]
+    pub fn test_covers_and_supremum() {
+        assert!(Shared.upgradable(Exclusive));
+        assert!(!Exclusive.upgradable(Shared));
+        assert!(IntentionShared.upgradable(IntentionExclusive));
+        assert_eq!(
+            SharedIntentionExclusive,
+            Shared.supremum(IntentionExclusive)
+        );
+        assert_eq!(
+            IntentionExclusive,
+            IntentionShared.supremum(IntentionExclusive)
+        );
+        assert_eq!(Exclusive, SharedIntentionExclusive.supremum(Exclusive));
+        assert_eq!(IntentionShared, Shared.intention());
+        assert_eq!(IntentionExclusive, Exclusive.intention());
+        assert_eq!(LockMode::NoLock, NoLock.intention());
+    }
+
+    #[test]
+    pub fn test_update_and_increment() {
+        assert!(Update.compatible(Shared) && Shared.compatible(Update));
+        assert!(!Update.compatible(Update));
+        assert!(!Update.compatible(Exclusive));
+        assert!(Update.compatible(IntentionShared));
+        assert!(Increment.compatible(Increment));
+        assert!(!Increment.compatible(Shared) && !Increment.compatible(Update));
+        assert!(Update.covers(Shared) && !Update.covers(Exclusive));
+        assert_eq!(Update, Shared.supremum(Update));
+        assert_eq!(Exclusive, Update.supremum(Increment));
+        assert_eq!(IntentionExclusive, Update.intention());
+        assert_eq!(IntentionExclusive, Increment.intention());
+    }
+
+    #[test]
+    pub fn test_group_mode() {
+        assert_eq!(NoLock, LockMode::group_mode([]));
+        assert_eq!(
+            Shared,
+            LockMode::group_mode([IntentionShared, Shared, Shared])
+        );
+        assert_eq!(
+            IntentionExclusive,
+            LockMode::group_mode([IntentionShared, IntentionExclusive, IntentionShared])
+        );
+        // The group is compatible with exactly what every holder is compatible with.
+        for first in LockMode::MODES {
+            for second in LockMode::MODES {
+                if !first.compatible(second) {
+                    continue;
+                }
+                let group = LockMode::group_mode([first, second]);
+                for require in LockMode::MODES {
+                    assert_eq!(
+                        first.compatible(require) && second.compatible(require),
+                        group.compatible(require),
+                        "{:?} {:?} {:?}",
+                        first,
+                        second,
+                        require
+                    );
+                }
+            }
+        }
+    }
+}
@@ -1,186 +1,1267 @@
 #![allow(clippy::map_entry)]
 
+use crate::dead_lock_detector::{
+    DeadLock, DealLockDetector, PeriodicDetection, VictimCandidate, VictimPolicy,
+};
 use crate::declare_locks_table;
 use crate::lock::{Lock, LockMode, OP_LOCK_MAPPING};
 use crate::lock_mgr::LockErrorCode::*;
 use crate::operation::Operation;
 use crate::segment::ResourceId;
+use crate::undo_log::UndoLog;
+use crate::wait_queue::{LockRequest, LockWaiter, WaitStatus};
 use anyhow::{anyhow, Result};
-use once_cell::sync::Lazy;
-use parking_lot::RwLock;
-use std::collections::HashMap;
-use std::thread::park_timeout;
+use parking_lot::{Mutex, MutexGuard, RwLock};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 use thiserror::Error;
 
 declare_locks_table!(OperationLockTable; Operation);
 declare_locks_table!(ResourceLockTable; ResourceId);
 
-static GLOBAL_LOCK_TABLE: Lazy<RwLock<LockTable>> = Lazy::new(|| {
-    let lock_table = LockTable::new();
-    RwLock::new(lock_table)
-});
+/// Number of buckets the resource and operation tables are split into.
+const LOCK_TABLE_SHARDS: usize = 64;
 
-#[derive(Debug, Default, Clone)]
-pub struct LockTable {
-    resource_table: HashMap<String, ResourceLockTable>,
+type ResourceShard = HashMap<ResourceId, ResourceLockTable>;
+
+#[derive(Debug, Default)]
+struct OperationShard {
     operation_table: HashMap<String, OperationLockTable>,
+    op_stats: HashMap<String, OpStats>,
+    /// Operations whose locks were taken away by an older Wound-Wait requester, the
+    /// error is reported on their next call.
+    wounded: HashMap<String, ResourceId>,
+    /// Undo logs rolled back when the lock table aborts their operation.
+    undo_logs: HashMap<String, Arc<UndoLog>>,
+}
+
+/// The resource and operation tables are hash sharded into buckets with a latch each,
+/// so requests on unrelated resources never serialize. A request latches the bucket of
+/// its resource first and then, briefly, the buckets of the operations it updates,
+/// never the other way around. Buckets of several resources are latched in bucket
+/// order. Work reaching into the locks of other operations (aborting them, resolving
+/// deadlocks) latches every resource bucket. The wait-for graph of the deadlock detector
+/// is shared by all requests too, but only requests that wait, and the operations they
+/// wait for, ever touch it.
+///
+/// Each table is an isolated lock domain, the lock managers sharing one `Arc<LockTable>`
+/// see each other's locks and nothing else.
+#[derive(Debug)]
+pub struct LockTable {
+    resource_shards: Vec<Mutex<ResourceShard>>,
+    operation_shards: Vec<Mutex<OperationShard>>,
+    detector: DealLockDetector,
+    victim_policy: RwLock<VictimPolicy>,
+    op_seq: AtomicU64,
+}
+
+/// Resource buckets latched together, see `LockTable::resource_shards`.
+struct ResourceShards<'a> {
+    lock_table: &'a LockTable,
+    guards: BTreeMap<usize, MutexGuard<'a, ResourceShard>>,
+}
+
+impl ResourceShards<'_> {
+    fn get(&mut self, rid: &ResourceId) -> &mut ResourceShard {
+        self.guards
+            .get_mut(&self.lock_table.shard_of(rid))
+            .expect("the bucket of every resource is latched")
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct OpStats {
+    started: u64,
+    work_done: usize,
+    timestamp: u128,
+    waiting_on: Option<ResourceId>,
+    /// The operation waits or was waited for, so it may have a node in the wait-for graph.
+    in_graph: bool,
+    /// Intention locks taken on behalf of children, with the number of children.
+    implicit_locks: HashMap<ResourceId, usize>,
+    /// Locks acquired as the target of a request, with the number of acquisitions not
+    /// released yet.
+    explicit_locks: HashMap<ResourceId, usize>,
+}
+
+impl OpStats {
+    /// Whether `rid` is still acquired explicitly or on behalf of a child.
+    fn in_use(&self, rid: &ResourceId) -> bool {
+        self.implicit_locks.contains_key(rid) || self.explicit_locks.contains_key(rid)
+    }
+}
+
+/// Drop one reference counted in `counts` for `rid`, forgetting it at zero. Returns
+/// whether `rid` was counted at all.
+fn release_count(counts: &mut HashMap<ResourceId, usize>, rid: &ResourceId) -> bool {
+    match counts.get_mut(rid) {
+        Some(count) if *count > 1 => *count -= 1,
+        Some(_) => {
+            counts.remove(rid);
+        }
+        None => return false,
+    }
+    true
+}
+
+/// Timestamp based deadlock prevention applied when a request has to wait.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Hash)]
+pub enum DeadLockPrevention {
+    /// Always wait, deadlocks are left to the `DealLockDetector`.
+    #[default]
+    NoPrevention,
+    /// An older requester waits for younger holders, a younger requester dies.
+    WaitDie,
+    /// An older requester wounds (aborts) younger holders, a younger requester waits.
+    WoundWait,
+}
+
+impl Default for LockTable {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl LockTable {
     pub fn new() -> Self {
         Self {
-            resource_table: HashMap::new(),
-            operation_table: HashMap::new(),
+            resource_shards: vec![],
+            operation_shards: vec![],
+            detector: DealLockDetector::new(),
+            victim_policy: RwLock::new(VictimPolicy::default()),
+            op_seq: AtomicU64::new(0),
+        }
+        .with_shards(LOCK_TABLE_SHARDS)
+    }
+
+    /// Split the tables into `shards` buckets, one bucket latches the whole table like
+    /// before sharding.
+    pub fn with_shards(mut self, shards: usize) -> Self {
+        assert!(shards > 0, "the tables need a bucket");
+        self.resource_shards = (0..shards).map(|_| Mutex::new(HashMap::new())).collect();
+        self.operation_shards = (0..shards)
+            .map(|_| Mutex::new(OperationShard::default()))
+            .collect();
+        self
+    }
+
+    /// Roll back `undo_log` whenever `op_id` gets aborted by the lock table, before its
+    /// locks are released.
+    pub fn register_undo_log(&self, op_id: &str, undo_log: Arc<UndoLog>) {
+        self.operation_shard(op_id)
+            .undo_logs
+            .insert(op_id.to_string(), undo_log);
+    }
+
+    pub fn remove_undo_log(&self, op_id: &str) {
+        self.operation_shard(op_id).undo_logs.remove(op_id);
+    }
+
+    /// The wait-for graph fed by every lock manager sharing the lock table.
+    pub fn dead_lock_detector(&self) -> DealLockDetector {
+        self.detector.clone()
+    }
+
+    /// Run cycle detection on demand over the current waits.
+    pub fn detect_dead_locks(&self) -> Vec<DeadLock> {
+        self.detector.detect()
+    }
+
+    /// The policy used by `resolve_dead_locks` and the periodic resolver.
+    pub fn set_victim_policy(&self, policy: VictimPolicy) {
+        *self.victim_policy.write() = policy;
+    }
+
+    /// Break every deadlock with the configured `VictimPolicy`, see `resolve_dead_locks_with`.
+    pub fn resolve_dead_locks(&self) -> Vec<String> {
+        let policy = self.victim_policy.read().clone();
+        self.resolve_dead_locks_with(&policy)
+    }
+
+    /// Abort one victim per cycle until the wait-for graph is acyclic and return the victims.
+    /// The blocked request of a victim fails with `Deadlock`, then all of its locks are
+    /// released so the survivors can proceed and the caller can retry the victim.
+    /// A custom policy naming an operation outside the cycle falls back to `Youngest`.
+    pub fn resolve_dead_locks_with(&self, policy: &VictimPolicy) -> Vec<String> {
+        let mut shards = self.all_resource_shards();
+        let mut victims = vec![];
+        while let Some(dead_lock) = self.detector.detect().into_iter().next() {
+            let candidates = dead_lock
+                .nodes
+                .iter()
+                .map(|node| self.victim_candidate(&node.op))
+                .collect::<Vec<_>>();
+            let victim_node = policy
+                .select(&candidates)
+                .and_then(|victim| dead_lock.nodes.iter().find(|node| node.op == victim))
+                .or_else(|| {
+                    let victim = VictimPolicy::Youngest.select(&candidates)?;
+                    dead_lock.nodes.iter().find(|node| node.op == victim)
+                })
+                .expect("a cycle has at least one operation")
+                .clone();
+            let err = Deadlock(victim_node.op.clone(), victim_node.rid.clone());
+            self.abort_waiter(
+                shards.get(&victim_node.rid),
+                &victim_node.op,
+                &victim_node.rid,
+                err.clone(),
+            );
+            self.abort_op(&mut shards, &victim_node.op, err);
+            // The victim waits for nobody anymore, an edge left behind must not make the
+            // same cycle show up again.
+            self.detector.remove_node(&victim_node.op);
+            victims.push(victim_node.op);
+        }
+        victims
+    }
+
+    /// Run `resolve_dead_locks` every `interval` on a background thread.
+    pub fn spawn_dead_lock_resolver(self: &Arc<Self>, interval: Duration) -> PeriodicDetection {
+        let lock_table = self.clone();
+        PeriodicDetection::spawn(interval, move || {
+            lock_table.resolve_dead_locks();
+        })
+    }
+
+    fn shard_of<K: Hash + ?Sized>(&self, key: &K) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        (hasher.finish() as usize) % self.resource_shards.len()
+    }
+
+    fn resource_shard(&self, rid: &ResourceId) -> MutexGuard<'_, ResourceShard> {
+        self.resource_shards[self.shard_of(rid)].lock()
+    }
+
+    /// Latch the buckets of all `rids` at once, in bucket order so that two callers can
+    /// never wait for each other.
+    fn resource_shards<'a>(
+        &self,
+        rids: impl Iterator<Item = &'a ResourceId>,
+    ) -> ResourceShards<'_> {
+        let mut shard_ids = rids.map(|rid| self.shard_of(rid)).collect::<Vec<_>>();
+        shard_ids.sort_unstable();
+        shard_ids.dedup();
+        ResourceShards {
+            lock_table: self,
+            guards: shard_ids
+                .into_iter()
+                .map(|shard_id| (shard_id, self.resource_shards[shard_id].lock()))
+                .collect(),
+        }
+    }
+
+    /// Latch every resource bucket, in bucket order, to work on the locks of operations
+    /// that may sit in any of them.
+    fn all_resource_shards(&self) -> ResourceShards<'_> {
+        ResourceShards {
+            lock_table: self,
+            guards: self
+                .resource_shards
+                .iter()
+                .map(|shard| shard.lock())
+                .enumerate()
+                .collect(),
+        }
+    }
+
+    fn operation_shard(&self, op_id: &str) -> MutexGuard<'_, OperationShard> {
+        self.operation_shards[self.shard_of(op_id)].lock()
+    }
+
+    fn resource_table(&self, rid: &ResourceId) -> Option<ResourceLockTable> {
+        self.resource_shard(rid).get(rid).cloned()
+    }
+
+    fn operation_table(&self, op_id: &str) -> Option<OperationLockTable> {
+        self.operation_shard(op_id)
+            .operation_table
+            .get(op_id)
+            .cloned()
+    }
+
+    /// Number of resources and of operations with locks or waits in the table.
+    fn table_sizes(&self) -> (usize, usize) {
+        let shards = self.all_resource_shards();
+        let res_size = shards.guards.values().map(|shard| shard.len()).sum();
+        let op_size = self
+            .operation_shards
+            .iter()
+            .map(|shard| shard.lock().operation_table.len())
+            .sum();
+        (res_size, op_size)
+    }
+
+    fn start_op(&self, operation: &Operation) {
+        let op_shard = &mut *self.operation_shard(&operation.id);
+        if !op_shard.op_stats.contains_key(&operation.id) {
+            let stats = OpStats {
+                started: self.op_seq.fetch_add(1, Ordering::Relaxed) + 1,
+                timestamp: operation.timestamp,
+                ..OpStats::default()
+            };
+            op_shard.op_stats.insert(operation.id.clone(), stats);
+        }
+    }
+
+    /// Total order on operations by timestamp, op ids break ties. `operation` may not be
+    /// in the table yet, `other_op_id` holds or waits for a lock.
+    fn is_older(&self, operation: &Operation, other_op_id: &str) -> bool {
+        let other_timestamp = self
+            .operation_shard(other_op_id)
+            .op_stats
+            .get(other_op_id)
+            .map(|stats| stats.timestamp)
+            .unwrap_or_default();
+        (operation.timestamp, operation.id.as_str()) < (other_timestamp, other_op_id)
+    }
+
+    fn victim_candidate(&self, op_id: &str) -> VictimCandidate {
+        let op_shard = &*self.operation_shard(op_id);
+        let stats = op_shard.op_stats.get(op_id).cloned().unwrap_or_default();
+        VictimCandidate {
+            op: op_id.to_string(),
+            started: stats.started,
+            locks_held: op_shard
+                .operation_table
+                .get(op_id)
+                .map(|ops_table| ops_table.lock_size())
+                .unwrap_or_default(),
+            work_done: stats.work_done,
+        }
+    }
+
+    fn wound(&self, op_id: &str, rid: &ResourceId) {
+        self.operation_shard(op_id)
+            .wounded
+            .insert(op_id.to_string(), rid.clone());
+    }
+
+    fn is_wounded(&self, op_id: &str) -> bool {
+        self.operation_shard(op_id).wounded.contains_key(op_id)
+    }
+
+    /// Report and forget the wound of `op_id`.
+    pub(crate) fn take_wounded(&self, op_id: &str) -> Result<()> {
+        match self.operation_shard(op_id).wounded.remove(op_id) {
+            Some(wounded_rid) => Err(anyhow!(Wounded(op_id.to_string(), wounded_rid))),
+            None => Ok(()),
+        }
+    }
+
+    /// Take the request of `op_id` out of the queue of `rid` and let the queue move on.
+    /// Returns the request when it was still waiting.
+    fn remove_waiter(
+        &self,
+        shard: &mut ResourceShard,
+        op_id: &str,
+        rid: &ResourceId,
+    ) -> Option<LockRequest> {
+        let request = shard
+            .get(rid)
+            .and_then(|res_table| res_table.remove_waiter(op_id));
+        self.detector.unlink_waiter(op_id, rid);
+        self.detector.unlink_holder(op_id, rid);
+        let holds_nothing = {
+            let op_shard = &mut *self.operation_shard(op_id);
+            if let Some(stats) = op_shard.op_stats.get_mut(op_id) {
+                stats.waiting_on = None;
+            }
+            !op_shard.operation_table.contains_key(op_id)
+                && op_shard.op_stats.remove(op_id).is_some()
+        };
+        if holds_nothing {
+            self.detector.remove_isolated(op_id);
+        }
+        // The request may have been the head blocking compatible requests behind it.
+        self.grant_waiters(shard, rid);
+        request
+    }
+
+    /// Fail the blocked request of `op_id` on `rid` with `err`.
+    fn abort_waiter(
+        &self,
+        shard: &mut ResourceShard,
+        op_id: &str,
+        rid: &ResourceId,
+        err: LockErrorCode,
+    ) {
+        if let Some(request) = self.remove_waiter(shard, op_id, rid) {
+            request.waiter.abort(err);
+        }
+    }
+
+    /// Release the lock `op_id` holds on `rid` and hand the resource to the next waiters.
+    fn release_lock(&self, shard: &mut ResourceShard, op_id: &str, rid: &ResourceId) {
+        if let Some(res_table) = shard.get(rid) {
+            res_table.remove_holder(op_id);
+        }
+        let in_graph = {
+            let op_shard = &mut *self.operation_shard(op_id);
+            let in_graph = op_shard
+                .op_stats
+                .get(op_id)
+                .is_some_and(|stats| stats.in_graph);
+            if let Some(ops_table) = op_shard.operation_table.get(op_id) {
+                ops_table.remove_lock(rid.clone());
+                if ops_table.lock_size() == 0_usize {
+                    op_shard.operation_table.remove(op_id);
+                    op_shard.op_stats.remove(op_id);
+                } else if let Some(stats) = op_shard.op_stats.get_mut(op_id) {
+                    stats.implicit_locks.remove(rid);
+                    stats.explicit_locks.remove(rid);
+                }
+            }
+            in_graph
+        };
+        // Uncontended locks stay away from the latch of the wait-for graph.
+        if in_graph {
+            self.detector.unlink_holder(op_id, rid);
+        }
+        self.grant_waiters(shard, rid);
+        if in_graph {
+            self.detector.remove_isolated(op_id);
+        }
+    }
+
+    /// Abort `op_id`: roll back its registered undo log, fail its blocked request with
+    /// `err`, if any, and release every lock it holds. Returns whether `err` was delivered
+    /// to a blocked request. The locks span any number of buckets, `shards` are all of them.
+    fn abort_op(&self, shards: &mut ResourceShards<'_>, op_id: &str, err: LockErrorCode) -> bool {
+        let undo_log = self.operation_shard(op_id).undo_logs.remove(op_id);
+        if let Some(undo_log) = undo_log {
+            undo_log.rollback();
+        }
+        let waiting_on = self
+            .operation_shard(op_id)
+            .op_stats
+            .get(op_id)
+            .and_then(|stats| stats.waiting_on.clone());
+        if let Some(rid) = waiting_on.as_ref() {
+            self.abort_waiter(shards.get(rid), op_id, rid, err);
+        }
+        let held_locks = self
+            .operation_table(op_id)
+            .map(|ops_table| ops_table.locks())
+            .unwrap_or_default();
+        for lock in held_locks {
+            self.release_lock(shards.get(&lock.rid), op_id, &lock.rid);
+        }
+        waiting_on.is_some()
+    }
+
+    /// Drop one child of the intention lock `op_id` implicitly holds on `rid`, releasing
+    /// it with the last child unless it was also acquired explicitly.
+    fn release_implicit(&self, shard: &mut ResourceShard, op_id: &str, rid: &ResourceId) {
+        let unused = match self.operation_shard(op_id).op_stats.get_mut(op_id) {
+            Some(stats) => release_count(&mut stats.implicit_locks, rid) && !stats.in_use(rid),
+            None => false,
+        };
+        if unused && self.holds(op_id, rid) {
+            self.release_lock(shard, op_id, rid);
+        }
+    }
+
+    /// Drop one acquisition of `rid` by `op_id`. The lock is released with the last one,
+    /// unless children still hold it implicitly.
+    fn release_explicit(&self, shard: &mut ResourceShard, op_id: &str, rid: &ResourceId) {
+        let in_use = match self.operation_shard(op_id).op_stats.get_mut(op_id) {
+            Some(stats) => {
+                release_count(&mut stats.explicit_locks, rid);
+                stats.in_use(rid)
+            }
+            None => false,
+        };
+        if !in_use {
+            self.release_lock(shard, op_id, rid);
+        }
+    }
+
+    /// Strengthen the lock the operation holds on `rid` so it covers `require_lock`. The
+    /// lock is converted in place when no other holder conflicts, the request does not
+    /// queue up behind waiters that may be waiting for the lock being upgraded. Otherwise
+    /// the upgrade is queued first and waits until the other holders are gone. A second
+    /// upgrade meanwhile would wait for the first one which waits for it, so it fails with
+    /// `UpgradeDeadlock` instead.
+    fn convert_lock(
+        &self,
+        shard: &mut ResourceShard,
+        operation: &Operation,
+        rid: &ResourceId,
+        require_lock: LockMode,
+        wait: bool,
+    ) -> Result<LockGrant> {
+        let op_id = &operation.id;
+        let held_mode = self.held_mode(op_id, rid);
+        if held_mode.covers(require_lock) {
+            return Ok(LockGrant::Held(Lock::new(
+                held_mode,
+                op_id.to_string(),
+                rid.clone(),
+            )));
+        }
+        let new_lock = Lock::new(
+            held_mode.supremum(require_lock),
+            op_id.to_string(),
+            rid.clone(),
+        );
+        let res_table = shard.get(rid).unwrap().clone();
+        if !res_table.lock_conflicts_except(new_lock.lock_mode, op_id) {
+            res_table.update_holder(new_lock.clone());
+            self.update_op_lock(&new_lock);
+            return Ok(LockGrant::Converted(new_lock, held_mode));
+        }
+        if !wait {
+            return Err(anyhow!(LockConflicts(op_id.to_string(), rid.clone())));
+        }
+        if res_table.has_upgrade() {
+            return Err(anyhow!(UpgradeDeadlock(op_id.to_string(), rid.clone())));
+        }
+        let blockers = self.blockers(shard, &new_lock, true);
+        let request = LockRequest::upgrade(operation.clone(), new_lock);
+        let waiter = request.waiter.clone();
+        self.enqueue(shard, request, &blockers);
+        Ok(LockGrant::Upgrading(waiter, held_mode))
+    }
+
+    /// Replace a held lock with the weaker `new_lock`. The queued requests compatible with
+    /// it no longer wait for its holder, those at the head of the queue are granted.
+    fn weaken_lock(&self, shard: &mut ResourceShard, new_lock: &Lock) {
+        let rid = &new_lock.rid;
+        let res_table = match shard.get(rid) {
+            Some(res_table) => res_table,
+            None => return,
+        };
+        res_table.update_holder(new_lock.clone());
+        self.update_op_lock(new_lock);
+        for queued in res_table.waiting_locks() {
+            if queued.lock_mode.compatible(new_lock.lock_mode) {
+                self.detector
+                    .unlink_edge(&queued.op_id, &new_lock.op_id, rid);
+            }
+        }
+        self.grant_waiters(shard, rid);
+    }
+
+    fn held_mode(&self, op_id: &str, rid: &ResourceId) -> LockMode {
+        self.operation_table(op_id)
+            .map(|ops_table| ops_table.get_lock_mode(rid.clone()))
+            .unwrap_or_default()
+    }
+
+    fn holds(&self, op_id: &str, rid: &ResourceId) -> bool {
+        self.operation_shard(op_id)
+            .operation_table
+            .get(op_id)
+            .map(|ops_table| ops_table.has_lock(rid))
+            .unwrap_or(false)
+    }
+
+    /// Replace the lock the operation of `lock` holds on the same resource.
+    fn update_op_lock(&self, lock: &Lock) {
+        let op_shard = &mut *self.operation_shard(&lock.op_id);
+        if let Some(stats) = op_shard.op_stats.get_mut(&lock.op_id) {
+            stats.work_done += 1;
+            stats.waiting_on = None;
+        }
+        if let Some(ops_table) = op_shard.operation_table.get(&lock.op_id) {
+            ops_table.update_lock(lock.clone(), lock.rid.clone());
+        }
+    }
+
+    fn add_op_lock(&self, operation: &Operation, lock: Lock) {
+        let op_shard = &mut *self.operation_shard(&lock.op_id);
+        if let Some(stats) = op_shard.op_stats.get_mut(&lock.op_id) {
+            stats.work_done += 1;
+            stats.waiting_on = None;
+        }
+        op_shard
+            .operation_table
+            .entry(lock.op_id.clone())
+            .or_insert_with(|| OperationLockTable::new(operation.clone()))
+            .add_lock(lock);
+    }
+
+    /// Hand the resource over to the queued requests that became compatible, in FIFO
+    /// order, and drop the resource entry once nobody holds or waits for it.
+    fn grant_waiters(&self, shard: &mut ResourceShard, rid: &ResourceId) {
+        let res_table = match shard.get(rid) {
+            Some(res_table) => res_table.clone(),
+            None => return,
+        };
+        while let Some(request) = res_table.pop_grantable() {
+            if request.upgrade {
+                self.update_op_lock(&request.lock);
+            } else {
+                self.add_op_lock(&request.operation, request.lock.clone());
+            }
+            self.detector.unlink_waiter(&request.lock.op_id, rid);
+            request.waiter.grant(request.lock);
+        }
+        if res_table.lock_size() == 0_usize && !res_table.has_waiters() {
+            shard.remove(rid);
+        }
+    }
+
+    /// The operations a request about to be queued would wait for: each incompatible
+    /// holder and, unless it is an `upgrade`, each incompatible request queued ahead of it.
+    fn blockers(&self, shard: &ResourceShard, request: &Lock, upgrade: bool) -> Vec<String> {
+        let res_table = match shard.get(&request.rid) {
+            Some(res_table) => res_table,
+            None => return vec![],
+        };
+        let queued = if upgrade {
+            vec![]
+        } else {
+            res_table.waiting_locks()
+        };
+        let mut blockers = res_table
+            .locks()
+            .into_iter()
+            .chain(queued)
+            .filter(|blocker| {
+                blocker.op_id != request.op_id && !blocker.lock_mode.compatible(request.lock_mode)
+            })
+            .map(|blocker| blocker.op_id)
+            .collect::<Vec<_>>();
+        blockers.dedup();
+        blockers
+    }
+
+    /// Queue `request` on its resource and feed the wait-for graph. The edges to the
+    /// blockers stay valid when a request ahead is granted, so only releases and
+    /// cancellations need to unlink. The requests an upgrade is queued ahead of now
+    /// wait for the upgrading operation as well.
+    fn enqueue(&self, shard: &ResourceShard, request: LockRequest, blockers: &[String]) {
+        let op_id = request.lock.op_id.clone();
+        let rid = request.lock.rid.clone();
+        self.detector.link_nodes(&op_id, blockers, &rid);
+        if let (true, Some(res_table)) = (request.upgrade, shard.get(&rid)) {
+            for queued in res_table.waiting_locks() {
+                if !queued.lock_mode.compatible(request.lock.lock_mode) {
+                    self.detector
+                        .link_nodes(&queued.op_id, std::slice::from_ref(&op_id), &rid);
+                }
+            }
+        }
+        if let Some(stats) = self.operation_shard(&op_id).op_stats.get_mut(&op_id) {
+            stats.waiting_on = Some(rid.clone());
+            stats.in_graph = true;
+        }
+        for blocker in blockers {
+            if let Some(stats) = self.operation_shard(blocker).op_stats.get_mut(blocker) {
+                stats.in_graph = true;
+            }
+        }
+        if let Some(res_table) = shard.get(&rid) {
+            res_table.enqueue(request);
         }
     }
 }
 
-#[derive(Error, Debug)]
+#[derive(Error, Debug, Clone, Eq, PartialEq)]
 pub enum LockErrorCode {
     #[error("Failed acquire for OP_ID {0}. Lock already exist.")]
     DuplicateLock(String),
     #[error("Failed No transaction holds a lock  for OP_ID {0}")]
     NoLockHeld(String),
     #[error("Acquire Lock conflicts OP_ID {0} RES_ID {1}")]
-    LockConflicts(String, String),
+    LockConflicts(String, ResourceId),
+    #[error("Timeout waiting for lock OP_ID {0} RES_ID {1}")]
+    LockWaitTimeout(String, ResourceId),
+    #[error("Aborted as deadlock victim OP_ID {0} RES_ID {1}")]
+    Deadlock(String, ResourceId),
+    #[error("Wait-Die aborted younger OP_ID {0} RES_ID {1}")]
+    WaitDieAbort(String, ResourceId),
+    #[error("Wound-Wait wounded by an older operation OP_ID {0} RES_ID {1}")]
+    Wounded(String, ResourceId),
+    #[error("Upgrade deadlock, another upgrade is pending OP_ID {0} RES_ID {1}")]
+    UpgradeDeadlock(String, ResourceId),
+    #[error("Only an exclusive lock can be downgraded OP_ID {0} RES_ID {1}")]
+    InvalidDowngrade(String, ResourceId),
+}
+
+enum LockGrant {
+    Granted(Lock),
+    /// The operation already holds a lock on the resource covering the request.
+    Held(Lock),
+    Waiting(Arc<LockWaiter>),
+    /// The lock the operation held in the given mode was converted in place.
+    Converted(Lock, LockMode),
+    /// The operation waits to convert the lock it holds in the given mode, see
+    /// `LockTable::convert_lock`.
+    Upgrading(Arc<LockWaiter>, LockMode),
 }
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct LockManager {
+    lock_table: Arc<LockTable>,
     operation: Operation,
+    prevention: DeadLockPrevention,
 }
 
 impl Default for LockManager {
     fn default() -> Self {
-        Self::new(Operation::default())
+        Self::new(Arc::new(LockTable::new()), Operation::default())
+    }
+}
+
+impl std::fmt::Debug for LockManager {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LockManager")
+            .field("operation", &self.operation)
+            .field("prevention", &self.prevention)
+            .finish_non_exhaustive()
     }
 }
 
 impl LockManager {
-    pub fn new(operation: Operation) -> Self {
-        Self { operation }
+    /// A manager locking the resources of `operation` in `lock_table`.
+    pub fn new(lock_table: Arc<LockTable>, operation: Operation) -> Self {
+        Self {
+            lock_table,
+            operation,
+            prevention: DeadLockPrevention::default(),
+        }
+    }
+
+    pub fn operation(&self) -> &Operation {
+        &self.operation
+    }
+
+    pub fn lock_table(&self) -> &Arc<LockTable> {
+        &self.lock_table
+    }
+
+    /// Decide with `prevention` instead of waiting unconditionally when this manager's
+    /// request conflicts.
+    pub fn with_prevention(mut self, prevention: DeadLockPrevention) -> Self {
+        self.prevention = prevention;
+        self
     }
 
+    /// Acquire the lock, waiting at most `retry_time_count` for conflicting holders to
+    /// release it. A zero duration never waits and fails with `LockConflicts` instead.
     pub fn try_acquire(&self, retry_time_count: Duration) -> Result<Lock> {
         if retry_time_count.as_millis() == 0 {
-            self.acquire()
+            self.acquire_path(false, |_, _| {
+                unreachable!("request without wait never enqueue")
+            })
         } else {
-            let start_park = Instant::now();
-            let mut timeout_remaining = retry_time_count;
-            loop {
-                park_timeout(timeout_remaining);
-                let elapsed = start_park.elapsed();
-                if elapsed >= retry_time_count {
-                    break;
+            self.acquire_with_timeout(Some(retry_time_count))
+        }
+    }
+
+    /// Acquire the lock, parking the current thread in the resource wait queue until
+    /// every conflicting holder has released it.
+    pub fn acquire(&self) -> Result<Lock> {
+        self.acquire_with_timeout(None)
+    }
+
+    fn acquire_with_timeout(&self, timeout: Option<Duration>) -> Result<Lock> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        self.acquire_path(true, |rid, waiter| {
+            let timeout =
+                deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            let status = waiter.wait(timeout);
+            self.wait_result(rid, status, &waiter)
+        })
+    }
+
+    /// Lock every resource of `lock_path` in order, calling `wait_fn` whenever a request
+    /// got queued. On failure the locks taken by this call are given back.
+    fn acquire_path<F>(&self, wait: bool, mut wait_fn: F) -> Result<Lock>
+    where
+        F: FnMut(&ResourceId, Arc<LockWaiter>) -> Result<Lock>,
+    {
+        let mut grants = vec![];
+        for (rid, lock_mode) in self.lock_path() {
+            let grant_rs = match self.request_lock(&rid, lock_mode, wait) {
+                Ok(LockGrant::Waiting(waiter)) => wait_fn(&rid, waiter).map(LockGrant::Granted),
+                Ok(LockGrant::Upgrading(waiter, prior)) => {
+                    wait_fn(&rid, waiter).map(|lock| LockGrant::Converted(lock, prior))
                 }
-                if let Ok(lock) = self.acquire() {
-                    return Ok(lock);
-                } else {
-                    timeout_remaining = retry_time_count - elapsed;
+                grant_rs => grant_rs,
+            };
+            match grant_rs {
+                Ok(grant) => grants.push(grant),
+                Err(err) => {
+                    self.rollback_path(&grants);
+                    return Err(err);
                 }
             }
-            self.acquire()
         }
+        Ok(self.finish_path(grants))
     }
 
-    pub fn acquire(&self) -> Result<Lock> {
-        let rid = self.operation.clone().resources;
-        let op_id = self.operation.id.clone();
+    /// Async flavour of `acquire`: the task yields to the runtime while it sits in the
+    /// resource wait queue instead of blocking the worker thread.
+    pub async fn acquire_async(&self) -> Result<Lock> {
+        self.acquire_async_with_timeout(None).await
+    }
+
+    /// Async flavour of `try_acquire`, fails with `LockWaitTimeout` once `timeout` elapsed.
+    pub async fn try_acquire_async(&self, timeout: Duration) -> Result<Lock> {
+        self.acquire_async_with_timeout(Some(timeout)).await
+    }
+
+    async fn acquire_async_with_timeout(&self, timeout: Option<Duration>) -> Result<Lock> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut grants = vec![];
+        for (rid, lock_mode) in self.lock_path() {
+            let grant_rs = match self.request_lock(&rid, lock_mode, true) {
+                Ok(LockGrant::Waiting(waiter)) => self
+                    .wait_async(&rid, &waiter, deadline, &grants, None)
+                    .await
+                    .map(LockGrant::Granted),
+                Ok(LockGrant::Upgrading(waiter, prior)) => self
+                    .wait_async(&rid, &waiter, deadline, &grants, Some(prior))
+                    .await
+                    .map(|lock| LockGrant::Converted(lock, prior)),
+                grant_rs => grant_rs,
+            };
+            match grant_rs {
+                Ok(grant) => grants.push(grant),
+                Err(err) => {
+                    self.rollback_path(&grants);
+                    return Err(err);
+                }
+            }
+        }
+        Ok(self.finish_path(grants))
+    }
+
+    /// Yield until the request queued on `rid` is granted, aborted or `deadline` passed.
+    async fn wait_async(
+        &self,
+        rid: &ResourceId,
+        waiter: &LockWaiter,
+        deadline: Option<Instant>,
+        grants: &[LockGrant],
+        prior: Option<LockMode>,
+    ) -> Result<Lock> {
+        let mut wait_guard = AsyncWaitGuard {
+            lock_mgr: self,
+            rid,
+            waiter,
+            grants,
+            prior,
+            waiting: true,
+        };
+        let status = match deadline {
+            Some(deadline) => tokio::time::timeout(
+                deadline.saturating_duration_since(Instant::now()),
+                waiter.wait_async(),
+            )
+            .await
+            .unwrap_or(WaitStatus::Waiting),
+            None => waiter.wait_async().await,
+        };
+        wait_guard.waiting = false;
+        self.wait_result(rid, status, waiter)
+    }
+
+    /// The resources to lock for the operation: every parent in the intention mode of the
+    /// requested lock, from the root down, then the targets themselves.
+    fn lock_path(&self) -> Vec<(ResourceId, LockMode)> {
         let require_lock = *OP_LOCK_MAPPING.get(&self.operation.op_type).unwrap();
+        let intention = require_lock.intention();
+        self.operation
+            .parents
+            .iter()
+            .map(|parent| (parent.clone(), intention))
+            .chain(
+                self.operation
+                    .targets()
+                    .into_iter()
+                    .map(|target| (target, require_lock)),
+            )
+            .collect()
+    }
 
-        let lock_table = &mut *GLOBAL_LOCK_TABLE.write();
-        let op_locks_table = &mut lock_table.operation_table;
-        let resource_lock_table = &mut lock_table.resource_table;
-        if op_locks_table.contains_key(&op_id) {
-            Err(anyhow!(DuplicateLock(op_id)))
-        } else {
-            let lock = if resource_lock_table.contains_key(&rid) {
-                let res_table = resource_lock_table.get(&rid).unwrap();
-                let old_lock = res_table.get_lock_mode(rid.clone());
-                let new_lock = Lock::new(require_lock, op_id.clone(), rid.clone());
-                // RW
-                if old_lock.upgradable(require_lock) {
-                    self.promote(new_lock.clone(), resource_lock_table, op_locks_table);
-                } else {
-                    if !old_lock.compatible(require_lock) {
-                        // TODO: if lock conflicts may be wait for other op to release lock.
-                        return Err(anyhow!(LockConflicts(op_id, rid)));
+    /// Count the child lock on every intention lock taken implicitly so they can be
+    /// released together with the last child. Returns the lock of the target resource, for
+    /// a tuple or a range one lock named after it in the group mode of its targets.
+    fn finish_path(&self, mut grants: Vec<LockGrant>) -> Lock {
+        let targets = grants
+            .split_off(self.operation.parents.len())
+            .into_iter()
+            .map(|grant| match grant {
+                LockGrant::Granted(lock)
+                | LockGrant::Held(lock)
+                | LockGrant::Converted(lock, _) => lock,
+                _ => unreachable!("the targets are granted after the parents"),
+            })
+            .collect::<Vec<_>>();
+        let op_shard = &mut *self.lock_table.operation_shard(&self.operation.id);
+        if let Some(stats) = op_shard.op_stats.get_mut(&self.operation.id) {
+            for grant in grants {
+                if let LockGrant::Granted(lock)
+                | LockGrant::Held(lock)
+                | LockGrant::Converted(lock, _) = grant
+                {
+                    *stats.implicit_locks.entry(lock.rid).or_default() += 1;
+                }
+            }
+            for target in targets.iter() {
+                *stats.explicit_locks.entry(target.rid.clone()).or_default() += 1;
+            }
+        }
+        match (&self.operation.tuple, &self.operation.range) {
+            (None, None) => targets
+                .into_iter()
+                .next()
+                .expect("an operation without tuple or range has one target"),
+            _ => Lock::new(
+                LockMode::group_mode(targets.iter().map(|lock| lock.lock_mode)),
+                self.operation.id.clone(),
+                self.operation.resources.clone(),
+            ),
+        }
+    }
+
+    /// Give back the locks of a failed `lock_path`: release the new ones and turn the
+    /// converted ones back into the mode held before.
+    fn rollback_path(&self, grants: &[LockGrant]) {
+        for grant in grants.iter().rev() {
+            self.rollback_grant(grant);
+        }
+    }
+
+    fn rollback_grant(&self, grant: &LockGrant) {
+        let lock_table = &*self.lock_table;
+        match grant {
+            LockGrant::Granted(lock) => lock_table.release_lock(
+                &mut lock_table.resource_shard(&lock.rid),
+                &lock.op_id,
+                &lock.rid,
+            ),
+            LockGrant::Converted(lock, prior) => lock_table.weaken_lock(
+                &mut lock_table.resource_shard(&lock.rid),
+                &Lock::new(*prior, lock.op_id.clone(), lock.rid.clone()),
+            ),
+            _ => {}
+        }
+    }
+
+    fn wait_result(
+        &self,
+        rid: &ResourceId,
+        status: WaitStatus,
+        waiter: &LockWaiter,
+    ) -> Result<Lock> {
+        match status {
+            WaitStatus::Granted(lock) => Ok(lock),
+            WaitStatus::Aborted(err) => Err(anyhow!(err)),
+            WaitStatus::Waiting => self.cancel_wait(rid, waiter),
+        }
+    }
+
+    /// Grant the lock right away when nobody is queued and every holder is compatible,
+    /// otherwise append the request to the resource wait queue (FIFO, no overtaking).
+    /// A lock the operation already holds is reused, or upgraded when the held mode is
+    /// too weak.
+    fn request_lock(
+        &self,
+        rid: &ResourceId,
+        require_lock: LockMode,
+        wait: bool,
+    ) -> Result<LockGrant> {
+        let lock_table = &*self.lock_table;
+        if let Some(grant) = self.try_request_lock(
+            lock_table,
+            &mut lock_table.resource_shard(rid),
+            rid,
+            require_lock,
+            wait,
+            self.prevention,
+        )? {
+            return Ok(grant);
+        }
+        // Aborting operations releases their locks in other buckets.
+        let mut shards = lock_table.all_resource_shards();
+        self.prevent_dead_lock(lock_table, &mut shards, rid, require_lock)?;
+        self.try_request_lock(
+            lock_table,
+            shards.get(rid),
+            rid,
+            require_lock,
+            wait,
+            DeadLockPrevention::NoPrevention,
+        )
+        .map(|grant| grant.expect("a request without prevention is granted or queued"))
+    }
+
+    /// One attempt of `request_lock` in `shard`, the latched bucket of `rid`. Returns
+    /// `None` when `prevention` has to abort an operation before the request may wait.
+    fn try_request_lock(
+        &self,
+        lock_table: &LockTable,
+        shard: &mut ResourceShard,
+        rid: &ResourceId,
+        require_lock: LockMode,
+        wait: bool,
+        prevention: DeadLockPrevention,
+    ) -> Result<Option<LockGrant>> {
+        let op_id = self.operation.id.clone();
+        let rid = rid.clone();
+        lock_table.take_wounded(&op_id)?;
+        if lock_table.holds(&op_id, &rid) {
+            return lock_table
+                .convert_lock(shard, &self.operation, &rid, require_lock, wait)
+                .map(Some);
+        }
+        let new_lock = Lock::new(require_lock, op_id.clone(), rid.clone());
+        let res_table = shard
+            .entry(rid.clone())
+            .or_insert_with(|| ResourceLockTable::new(rid.clone()))
+            .clone();
+        // The operation enters the table with its first lock or wait only.
+        if !res_table.has_waiters() && !res_table.lock_conflicts(require_lock) {
+            lock_table.start_op(&self.operation);
+            res_table.add_lock(new_lock.clone());
+            lock_table.add_op_lock(&self.operation, new_lock.clone());
+            return Ok(Some(LockGrant::Granted(new_lock)));
+        }
+        if !wait {
+            return Err(anyhow!(LockConflicts(op_id, rid)));
+        }
+        let blockers = lock_table.blockers(shard, &new_lock, false);
+        let must_abort = match prevention {
+            DeadLockPrevention::NoPrevention => false,
+            DeadLockPrevention::WaitDie => blockers
+                .iter()
+                .any(|blocker| !lock_table.is_older(&self.operation, blocker)),
+            DeadLockPrevention::WoundWait => blockers
+                .iter()
+                .any(|blocker| lock_table.is_older(&self.operation, blocker)),
+        };
+        if must_abort {
+            return Ok(None);
+        }
+        lock_table.start_op(&self.operation);
+        let request = LockRequest::new(self.operation.clone(), new_lock);
+        let waiter = request.waiter.clone();
+        lock_table.enqueue(shard, request, &blockers);
+        Ok(Some(LockGrant::Waiting(waiter)))
+    }
+
+    /// Apply `prevention` to the operations blocking the request on `rid`: Wait-Die
+    /// aborts this operation if any blocker is older, Wound-Wait aborts the younger
+    /// blockers. `shards` are all resource buckets.
+    fn prevent_dead_lock(
+        &self,
+        lock_table: &LockTable,
+        shards: &mut ResourceShards<'_>,
+        rid: &ResourceId,
+        require_lock: LockMode,
+    ) -> Result<()> {
+        let op_id = self.operation.id.clone();
+        if lock_table.is_wounded(&op_id) || lock_table.holds(&op_id, rid) {
+            return Ok(());
+        }
+        let new_lock = Lock::new(require_lock, op_id.clone(), rid.clone());
+        let blockers = lock_table.blockers(shards.get(rid), &new_lock, false);
+        match self.prevention {
+            DeadLockPrevention::NoPrevention => {}
+            DeadLockPrevention::WaitDie => {
+                if blockers
+                    .iter()
+                    .any(|blocker| !lock_table.is_older(&self.operation, blocker))
+                {
+                    lock_table.abort_op(shards, &op_id, WaitDieAbort(op_id.clone(), rid.clone()));
+                    return Err(anyhow!(WaitDieAbort(op_id, rid.clone())));
+                }
+            }
+            DeadLockPrevention::WoundWait => {
+                for blocker in blockers.iter() {
+                    if lock_table.is_older(&self.operation, blocker) {
+                        let err = Wounded(blocker.clone(), rid.clone());
+                        if !lock_table.abort_op(shards, blocker, err) {
+                            lock_table.wound(blocker, rid);
+                        }
                     }
-                    res_table.add_lock(new_lock.clone());
-                    let ops_table = OperationLockTable::new(self.operation.clone());
-                    ops_table.add_lock(new_lock.clone());
-                    op_locks_table.insert(op_id, ops_table);
                 }
-                new_lock
-            } else {
-                let new_lock = Lock::new(require_lock, op_id.clone(), rid.clone());
-                // add new_lock for locks table
-                let res_table = ResourceLockTable::new(rid.clone());
-                res_table.add_lock(new_lock.clone());
-                let ops_table = OperationLockTable::new(self.operation.clone());
-                ops_table.add_lock(new_lock.clone());
-                resource_lock_table.insert(rid, res_table);
-                op_locks_table.insert(op_id, ops_table);
-                new_lock
-            };
-            Ok(lock)
+            }
+        }
+        Ok(())
+    }
+
+    /// Leave the wait queue after a timeout. The grant may have raced with the timeout,
+    /// in which case the lock is already ours.
+    fn cancel_wait(&self, rid: &ResourceId, waiter: &LockWaiter) -> Result<Lock> {
+        let op_id = self.operation.id.clone();
+        let lock_table = &*self.lock_table;
+        let shard = &mut *lock_table.resource_shard(rid);
+        match waiter.status() {
+            WaitStatus::Granted(lock) => Ok(lock),
+            WaitStatus::Aborted(err) => Err(anyhow!(err)),
+            WaitStatus::Waiting => {
+                lock_table.remove_waiter(shard, &op_id, rid);
+                Err(anyhow!(LockWaitTimeout(op_id, rid.clone())))
+            }
+        }
+    }
+
+    /// Convert the `Exclusive` lock held on the operation's resource into `Shared` and
+    /// grant the queued requests that became compatible, while the operation keeps
+    /// reading what it wrote. Other modes are refused, a `SharedIntentionExclusive` lock
+    /// turned `Shared` would let readers in above the children the operation writes.
+    pub fn downgrade(&self) -> Result<Lock> {
+        let op_id = &self.operation.id;
+        let targets = self.operation.targets();
+        let lock_table = &*self.lock_table;
+        let mut shards = lock_table.resource_shards(targets.iter());
+        lock_table.take_wounded(op_id)?;
+        for rid in targets.iter() {
+            if !lock_table.holds(op_id, rid) {
+                return Err(anyhow!(NoLockHeld(op_id.clone())));
+            }
+            if lock_table.held_mode(op_id, rid) != LockMode::Exclusive {
+                return Err(anyhow!(InvalidDowngrade(op_id.clone(), rid.clone())));
+            }
         }
+        for rid in targets.iter() {
+            let new_lock = Lock::new(LockMode::Shared, op_id.clone(), rid.clone());
+            lock_table.weaken_lock(shards.get(rid), &new_lock);
+        }
+        Ok(Lock::new(
+            LockMode::Shared,
+            op_id.clone(),
+            self.operation.resources.clone(),
+        ))
     }
 
+    /// Release the lock on the operation's resource, then every intention lock on its
+    /// parents that no other lock of the operation needs anymore.
     pub fn release(&self) -> Result<()> {
-        let op_id = self.operation.clone().id;
-        let lock_table = &mut *GLOBAL_LOCK_TABLE.write();
-        let op_locks_table = &mut lock_table.operation_table;
-        let resource_lock_table = &mut lock_table.resource_table;
-        // println!("Release = {:?} {:?}", op_locks_table.clone(), resource_lock_table.clone());
-        if !op_locks_table.contains_key(&op_id) {
-            Err(anyhow!(NoLockHeld(op_id)))
-        } else {
-            let rid = self.operation.clone().resources;
-            let res_table = resource_lock_table.get_mut(&rid).unwrap();
-            res_table.remove_lock(rid.clone());
-            if res_table.lock_size() == 0_usize {
-                resource_lock_table.remove(&rid);
+        Self::release_all(std::slice::from_ref(self))
+    }
+
+    /// Release the locks of every manager, all sharing one lock table, in order while
+    /// latching all their buckets, so
+    /// no other operation can observe (and get granted in between) a partial release.
+    /// The first error is returned after trying all of them.
+    pub fn release_all(lock_mgrs: &[LockManager]) -> Result<()> {
+        let lock_table = match lock_mgrs.first() {
+            Some(lock_mgr) => &*lock_mgr.lock_table,
+            None => return Ok(()),
+        };
+        debug_assert!(lock_mgrs
+            .iter()
+            .all(|lock_mgr| std::ptr::eq(&*lock_mgr.lock_table, lock_table)));
+        let rids = lock_mgrs
+            .iter()
+            .flat_map(|lock_mgr| {
+                let operation = &lock_mgr.operation;
+                operation
+                    .targets()
+                    .into_iter()
+                    .chain(operation.parents.clone())
+            })
+            .collect::<Vec<_>>();
+        let mut shards = lock_table.resource_shards(rids.iter());
+        let mut release_rs = Ok(());
+        for lock_mgr in lock_mgrs.iter() {
+            let lock_rs = lock_mgr.release_latched(lock_table, &mut shards);
+            if release_rs.is_ok() {
+                release_rs = lock_rs;
             }
-            op_locks_table.remove(&op_id);
-            Ok(())
         }
+        release_rs
     }
 
-    fn promote(
+    fn release_latched(
         &self,
-        new_lock: Lock,
-        resource_lock_table: &mut HashMap<String, ResourceLockTable>,
-        op_locks_table: &mut HashMap<String, OperationLockTable>,
-    ) {
-        println!(
-            "LockManager lock promote. {:?} {:?}",
-            self.operation, new_lock
-        );
-        let rid = self.operation.clone().resources;
-        let res_table = resource_lock_table.get(&rid).unwrap();
-        let ops_table = op_locks_table.get(&self.operation.id).unwrap();
+        lock_table: &LockTable,
+        shards: &mut ResourceShards<'_>,
+    ) -> Result<()> {
+        let op_id = &self.operation.id;
+        let targets = self.operation.targets();
+        lock_table.take_wounded(op_id)?;
+        if !targets.iter().all(|rid| lock_table.holds(op_id, rid)) {
+            return Err(anyhow!(NoLockHeld(op_id.clone())));
+        }
+        for rid in targets.iter().rev() {
+            lock_table.release_explicit(shards.get(rid), op_id, rid);
+        }
+        for parent in self.operation.parents.iter().rev() {
+            lock_table.release_implicit(shards.get(parent), op_id, parent);
+        }
+        Ok(())
+    }
+}
+
+/// Withdraws the request when an `acquire_async` future is dropped while still queued,
+/// otherwise the resource would later be granted to nobody and never released.
+struct AsyncWaitGuard<'a> {
+    lock_mgr: &'a LockManager,
+    rid: &'a ResourceId,
+    waiter: &'a LockWaiter,
+    /// Parents already locked by the dropped call.
+    grants: &'a [LockGrant],
+    /// The request converts a lock held before the call in this mode, which is kept.
+    prior: Option<LockMode>,
+    waiting: bool,
+}
 
-        res_table.update_lock(new_lock.clone(), rid.clone());
-        ops_table.update_lock(new_lock, rid.clone());
+impl Drop for AsyncWaitGuard<'_> {
+    fn drop(&mut self) {
+        if !self.waiting {
+            return;
+        }
+        if let Ok(lock) = self.lock_mgr.cancel_wait(self.rid, self.waiter) {
+            let grant = match self.prior {
+                Some(prior) => LockGrant::Converted(lock, prior),
+                None => LockGrant::Granted(lock),
+            };
+            self.lock_mgr.rollback_grant(&grant);
+        }
+        self.lock_mgr.rollback_path(self.grants);
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::lock_mgr::{LockManager, GLOBAL_LOCK_TABLE};
+    use crate::dead_lock_detector::VictimPolicy;
+    use crate::lock::LockMode;
+    use crate::lock_mgr::{DeadLockPrevention, LockErrorCode, LockManager, LockTable};
     use crate::operation::OpType::*;
     use crate::operation::Operation;
+    use crate::segment::{ResourceId, Segment};
+    use std::sync::Arc;
     use std::time::Duration;
 
+    fn waiters_size(lock_table: &Arc<LockTable>, rid: impl Into<ResourceId>) -> usize {
+        lock_table
+            .resource_table(&rid.into())
+            .map(|res_table| res_table.waiters_size())
+            .unwrap_or_default()
+    }
+
+    fn wait_for_waiters(lock_table: &Arc<LockTable>, rid: impl Into<ResourceId>, expect: usize) {
+        let rid = rid.into();
+        while waiters_size(lock_table, &rid) != expect {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
     #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
     pub async fn test_acquire_multi_state() {
+        let lock_table = Arc::new(LockTable::new());
         let mut join_handlers = vec![];
         for idx in 0..2_i32 {
+            let lock_table = lock_table.clone();
             let join = tokio::task::spawn(async move {
-                let lock_mgr =
-                    LockManager::new(Operation::new(idx.to_string(), "1,2,3".to_string(), Read));
+                let lock_mgr = LockManager::new(
+                    lock_table.clone(),
+                    Operation::new(format!("multi_{}", idx), "4,5,6".to_string(), Read),
+                );
                 let lock = lock_mgr.acquire();
                 assert!(lock.is_ok());
                 println!("lock_test_rs = {:?}", lock);
@@ -189,16 +1270,32 @@ mod tests {
         }
         let handlers_await = futures::future::join_all(join_handlers);
         let _await_rs = handlers_await.await;
-        let final_lock_table = &*GLOBAL_LOCK_TABLE.read();
-        let rs_table = &final_lock_table.resource_table;
-        let op_table = &final_lock_table.operation_table;
-        assert_eq!(1, rs_table.len());
-        assert_eq!(2, op_table.len());
-        println!("LockTable = {:#?}", final_lock_table);
+        let rs_table = lock_table.resource_table(&"4,5,6".into());
+        assert_eq!(2, rs_table.unwrap().lock_size());
+        assert!(lock_table.operation_table("multi_0").is_some());
+        assert!(lock_table.operation_table("multi_1").is_some());
+        let (res_size, op_size) = lock_table.table_sizes();
+        assert_eq!((1, 2), (res_size, op_size));
+    }
+
+    #[test]
+    pub fn test_isolated_lock_tables() {
+        let (first_table, second_table) = (Arc::new(LockTable::new()), Arc::new(LockTable::new()));
+        let operation = Operation::new("isolated".to_string(), "1,2,3".to_string(), Write);
+        let first = LockManager::new(first_table.clone(), operation.clone());
+        let second = LockManager::new(second_table.clone(), operation);
+        assert!(first.try_acquire(Duration::ZERO).is_ok());
+        assert!(second.try_acquire(Duration::ZERO).is_ok());
+
+        first.release().unwrap();
+        assert_eq!((0, 0), first_table.table_sizes());
+        assert_eq!((1, 1), second_table.table_sizes());
+        second.release().unwrap();
     }
 
     #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
     pub async fn test_wait_lock_state() {
+        let lock_table = Arc::new(LockTable::new());
         let resource_id = "1,2,3".to_string();
         let read_op_id = "1".to_string();
         let write_op_id = "2".to_string();
@@ -206,30 +1303,23 @@ mod tests {
         let read_op = Operation::new(read_op_id, resource_id.clone(), Read);
 
         let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let (read_table, write_table) = (lock_table.clone(), lock_table.clone());
 
-        tokio::task::spawn(async move {
+        let read_lock_join = tokio::task::spawn(async move {
             let recv_write_lock = rx.recv().await;
             println!(
                 "receive write lock success acquire READ_LOCK lock = {:?}",
                 recv_write_lock
             );
-            let lock_mgr = LockManager::new(read_op);
-            //  loop {
-            //      if let Ok(lock) = lock_mgr.acquire() {
-            //          println!("S Lock success.lock = {:?}", lock);
-            //          break;
-            //      } else {
-            //          println!("S Lock Err");
-            //      }
-            //      std::thread::sleep(Duration::from_millis(10));
-            //  }
-            let lock_rs = lock_mgr.try_acquire(Duration::from_millis(50));
+            let lock_mgr = LockManager::new(read_table, read_op);
+            let lock_rs = lock_mgr.try_acquire(Duration::from_millis(500));
             println!("S Lock lock = {:?}", lock_rs);
             assert!(lock_rs.is_ok());
+            lock_mgr.release().unwrap();
         });
 
         let write_lock_join = tokio::task::spawn(async move {
-            let write_lock_mgr = LockManager::new(write_op);
+            let write_lock_mgr = LockManager::new(write_table, write_op);
             let write_lock_rs = write_lock_mgr.acquire();
             assert!(write_lock_rs.is_ok());
             let send_lock_rs = tx.send(write_lock_rs.unwrap()).await;
@@ -244,14 +1334,984 @@ mod tests {
             assert!(write_release.is_ok());
             println!("X Lock Release Success");
         }
+        read_lock_join.await.unwrap();
     }
 
     #[test]
     pub fn test_lock_unlock() {
-        let lock_mgr = LockManager::new(Operation::new("1".to_string(), "1,2,3".to_string(), Read));
+        let lock_table = Arc::new(LockTable::new());
+        let lock_mgr = LockManager::new(
+            lock_table.clone(),
+            Operation::new("3".to_string(), "7,8,9".to_string(), Read),
+        );
         let lock_rs = lock_mgr.acquire();
         assert!(lock_rs.is_ok());
         let unlock_rs = lock_mgr.release();
         assert!(unlock_rs.is_ok());
     }
+
+    #[test]
+    pub fn test_wait_queue_fifo() {
+        let lock_table = Arc::new(LockTable::new());
+        let rid = "fifo".to_string();
+        let holder = LockManager::new(
+            lock_table.clone(),
+            Operation::new("fifo_x1".to_string(), rid.clone(), Write),
+        );
+        holder.acquire().unwrap();
+
+        let writer = std::thread::spawn({
+            let rid = rid.clone();
+            let lock_table = lock_table.clone();
+            move || {
+                let lock_mgr = LockManager::new(
+                    lock_table.clone(),
+                    Operation::new("fifo_x2".to_string(), rid, Write),
+                );
+                let lock = lock_mgr.acquire().unwrap();
+                std::thread::sleep(Duration::from_millis(20));
+                lock_mgr.release().unwrap();
+                lock
+            }
+        });
+        wait_for_waiters(&lock_table, &rid, 1);
+        // Compatible with nothing queued ahead of it, but must not overtake the writer.
+        let reader = std::thread::spawn({
+            let rid = rid.clone();
+            let lock_table = lock_table.clone();
+            move || {
+                let lock_mgr = LockManager::new(
+                    lock_table.clone(),
+                    Operation::new("fifo_s".to_string(), rid, Read),
+                );
+                let lock = lock_mgr.acquire().unwrap();
+                lock_mgr.release().unwrap();
+                lock
+            }
+        });
+        wait_for_waiters(&lock_table, &rid, 2);
+
+        holder.release().unwrap();
+        assert_eq!(LockMode::Exclusive, writer.join().unwrap().lock_mode);
+        assert_eq!(LockMode::Shared, reader.join().unwrap().lock_mode);
+        assert!(lock_table.resource_table(&rid.clone().into()).is_none());
+        // The waiters and the holder they waited for leave the wait-for graph as well.
+        assert_eq!(0, lock_table.dead_lock_detector().node_size());
+    }
+
+    #[test]
+    pub fn test_no_starvation_behind_writer() {
+        let lock_table = Arc::new(LockTable::new());
+        let rid = "starve".to_string();
+        let reader = LockManager::new(
+            lock_table.clone(),
+            Operation::new("starve_s1".to_string(), rid.clone(), Read),
+        );
+        reader.acquire().unwrap();
+
+        let writer = std::thread::spawn({
+            let rid = rid.clone();
+            let lock_table = lock_table.clone();
+            move || {
+                let lock_mgr = LockManager::new(
+                    lock_table.clone(),
+                    Operation::new("starve_x".to_string(), rid, Write),
+                );
+                lock_mgr.acquire().unwrap();
+                lock_mgr.release().unwrap();
+            }
+        });
+        wait_for_waiters(&lock_table, &rid, 1);
+
+        let late_reader = LockManager::new(
+            lock_table.clone(),
+            Operation::new("starve_s2".to_string(), rid.clone(), Read),
+        );
+        let late_rs = late_reader.try_acquire(Duration::ZERO);
+        assert!(matches!(
+            late_rs.unwrap_err().downcast_ref::<LockErrorCode>(),
+            Some(LockErrorCode::LockConflicts(_, _))
+        ));
+
+        reader.release().unwrap();
+        writer.join().unwrap();
+        assert!(late_reader.try_acquire(Duration::ZERO).is_ok());
+        late_reader.release().unwrap();
+    }
+
+    #[test]
+    pub fn test_sharded_concurrent_resources() {
+        // A single bucket behaves the same, only slower.
+        for lock_table in [LockTable::new(), LockTable::new().with_shards(1)] {
+            let lock_table = Arc::new(lock_table);
+            let workers = (0..8)
+                .map(|worker| {
+                    let lock_table = lock_table.clone();
+                    std::thread::spawn(move || {
+                        for idx in 0..200 {
+                            let op_id = format!("shard_{}_{}", worker, idx);
+                            let own = lock_mgr(
+                                &lock_table,
+                                &op_id,
+                                &format!("shard_r_{}_{}", worker, idx),
+                            );
+                            let shared = LockManager::new(
+                                lock_table.clone(),
+                                Operation::new(op_id.clone(), "shard_shared".to_string(), Read)
+                                    .with_parents(vec!["shard_segment".into()]),
+                            );
+                            own.acquire().unwrap();
+                            shared.acquire().unwrap();
+                            LockManager::release_all(&[shared, own]).unwrap();
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+            for worker in workers {
+                worker.join().unwrap();
+            }
+            for rid in [
+                "shard_shared",
+                "shard_segment",
+                "shard_r_0_0",
+                "shard_r_7_199",
+            ] {
+                assert!(lock_table.resource_table(&rid.into()).is_none());
+            }
+            assert!(lock_table.operation_table("shard_3_42").is_none());
+        }
+    }
+
+    #[test]
+    pub fn test_wait_timeout() {
+        let lock_table = Arc::new(LockTable::new());
+        let rid = "timeout".to_string();
+        let holder = LockManager::new(
+            lock_table.clone(),
+            Operation::new("timeout_x".to_string(), rid.clone(), Write),
+        );
+        holder.acquire().unwrap();
+
+        let waiter = LockManager::new(
+            lock_table.clone(),
+            Operation::new("timeout_s".to_string(), rid.clone(), Read),
+        );
+        let wait_rs = waiter.try_acquire(Duration::from_millis(20));
+        assert!(matches!(
+            wait_rs.unwrap_err().downcast_ref::<LockErrorCode>(),
+            Some(LockErrorCode::LockWaitTimeout(_, _))
+        ));
+        assert_eq!(0, waiters_size(&lock_table, &rid));
+        holder.release().unwrap();
+        assert!(waiter.try_acquire(Duration::ZERO).is_ok());
+        waiter.release().unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    pub async fn test_acquire_async_many_waiters() {
+        let lock_table = Arc::new(LockTable::new());
+        let rid = "async_many".to_string();
+        let holder = LockManager::new(
+            lock_table.clone(),
+            Operation::new("async_x".to_string(), rid.clone(), Write),
+        );
+        holder.acquire_async().await.unwrap();
+
+        // Far more waiting tasks than worker threads, none of them may block a worker.
+        let mut join_handlers = vec![];
+        for idx in 0..1000_i32 {
+            let rid = rid.clone();
+            let lock_table = lock_table.clone();
+            join_handlers.push(tokio::task::spawn(async move {
+                let op_type = if idx % 10 == 0 { Write } else { Read };
+                let lock_mgr = LockManager::new(
+                    lock_table.clone(),
+                    Operation::new(format!("async_{}", idx), rid, op_type),
+                );
+                lock_mgr.acquire_async().await.unwrap();
+                tokio::task::yield_now().await;
+                lock_mgr.release().unwrap();
+            }));
+        }
+        while waiters_size(&lock_table, &rid) != 1000 {
+            tokio::task::yield_now().await;
+        }
+        holder.release().unwrap();
+        for join in futures::future::join_all(join_handlers).await {
+            join.unwrap();
+        }
+        assert!(lock_table.resource_table(&rid.clone().into()).is_none());
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    pub async fn test_acquire_async_timeout() {
+        let lock_table = Arc::new(LockTable::new());
+        let rid = "async_timeout".to_string();
+        let holder = LockManager::new(
+            lock_table.clone(),
+            Operation::new("async_timeout_x".to_string(), rid.clone(), Write),
+        );
+        holder.acquire_async().await.unwrap();
+
+        let waiter = LockManager::new(
+            lock_table.clone(),
+            Operation::new("async_timeout_s".to_string(), rid.clone(), Read),
+        );
+        let wait_rs = waiter.try_acquire_async(Duration::from_millis(20)).await;
+        assert!(matches!(
+            wait_rs.unwrap_err().downcast_ref::<LockErrorCode>(),
+            Some(LockErrorCode::LockWaitTimeout(_, _))
+        ));
+        assert_eq!(0, waiters_size(&lock_table, &rid));
+
+        // A dropped future must leave the queue as well.
+        let dropped = tokio::time::timeout(Duration::from_millis(20), waiter.acquire_async()).await;
+        assert!(dropped.is_err());
+        assert_eq!(0, waiters_size(&lock_table, &rid));
+
+        holder.release().unwrap();
+        assert!(waiter.acquire_async().await.is_ok());
+        waiter.release().unwrap();
+    }
+
+    #[test]
+    pub fn test_detect_dead_lock() {
+        let lock_table = Arc::new(LockTable::new());
+        let (r1, r2) = ("dl_r1".to_string(), "dl_r2".to_string());
+        let t1_r1 = LockManager::new(
+            lock_table.clone(),
+            Operation::new("dl_t1".to_string(), r1.clone(), Write),
+        );
+        let t2_r2 = LockManager::new(
+            lock_table.clone(),
+            Operation::new("dl_t2".to_string(), r2.clone(), Write),
+        );
+        t1_r1.acquire().unwrap();
+        t2_r2.acquire().unwrap();
+
+        let t1_r2 = LockManager::new(
+            lock_table.clone(),
+            Operation::new("dl_t1".to_string(), r2.clone(), Write),
+        );
+        let t2_r1 = LockManager::new(
+            lock_table.clone(),
+            Operation::new("dl_t2".to_string(), r1.clone(), Write),
+        );
+        let t1_wait = std::thread::spawn(move || t1_r2.try_acquire(Duration::from_millis(300)));
+        let t2_wait = std::thread::spawn(move || t2_r1.try_acquire(Duration::from_millis(300)));
+        wait_for_waiters(&lock_table, &r1, 1);
+        wait_for_waiters(&lock_table, &r2, 1);
+
+        let mut dead_locks = lock_table.detect_dead_locks();
+        assert_eq!(1, dead_locks.len());
+        let dead_lock = dead_locks.remove(0);
+        let mut ops = dead_lock.ops();
+        ops.sort();
+        assert_eq!(vec!["dl_t1", "dl_t2"], ops);
+        let mut resources = dead_lock.resources();
+        resources.sort();
+        assert_eq!(vec![r1.clone(), r2.clone()], resources);
+
+        assert!(t1_wait.join().unwrap().is_err());
+        assert!(t2_wait.join().unwrap().is_err());
+        let detector = lock_table.dead_lock_detector();
+        assert!(!detector.has_outgoing("dl_t1"));
+        assert!(!detector.has_outgoing("dl_t2"));
+        t1_r1.release().unwrap();
+        t2_r2.release().unwrap();
+    }
+
+    fn lock_mgr(lock_table: &Arc<LockTable>, op_id: &str, rid: &str) -> LockManager {
+        LockManager::new(
+            lock_table.clone(),
+            Operation::new(op_id.to_string(), rid.to_string(), Write),
+        )
+    }
+
+    /// `first` holds `r1` and waits for `r2`, `second` holds `r2` and waits for `r1`.
+    fn dead_lock_scenario(
+        lock_table: &Arc<LockTable>,
+        first: &str,
+        second: &str,
+        r1: &str,
+        r2: &str,
+    ) -> (
+        std::thread::JoinHandle<anyhow::Result<crate::lock::Lock>>,
+        std::thread::JoinHandle<anyhow::Result<crate::lock::Lock>>,
+    ) {
+        let first_wait = lock_mgr(lock_table, first, r2);
+        let second_wait = lock_mgr(lock_table, second, r1);
+        let first_join = std::thread::spawn(move || first_wait.acquire());
+        wait_for_waiters(lock_table, r2, 1);
+        let second_join = std::thread::spawn(move || second_wait.acquire());
+        wait_for_waiters(lock_table, r1, 1);
+        (first_join, second_join)
+    }
+
+    #[test]
+    pub fn test_resolve_dead_lock_youngest() {
+        let lock_table = Arc::new(LockTable::new());
+        lock_mgr(&lock_table, "vy_t1", "vy_r1").acquire().unwrap();
+        lock_mgr(&lock_table, "vy_t2", "vy_r2").acquire().unwrap();
+        let (t1_join, t2_join) =
+            dead_lock_scenario(&lock_table, "vy_t1", "vy_t2", "vy_r1", "vy_r2");
+
+        let victims = lock_table.resolve_dead_locks_with(&VictimPolicy::Youngest);
+        assert_eq!(vec!["vy_t2".to_string()], victims);
+        assert!(matches!(
+            t2_join.join().unwrap().unwrap_err().downcast_ref::<LockErrorCode>(),
+            Some(LockErrorCode::Deadlock(op, rid)) if op == "vy_t2" && rid == "vy_r1"
+        ));
+        assert!(t1_join.join().unwrap().is_ok());
+        // The victim lost every lock it held.
+        assert!(lock_mgr(&lock_table, "vy_t2", "vy_r2").release().is_err());
+        lock_mgr(&lock_table, "vy_t1", "vy_r1").release().unwrap();
+        lock_mgr(&lock_table, "vy_t1", "vy_r2").release().unwrap();
+        assert!(lock_table.detect_dead_locks().is_empty());
+    }
+
+    #[test]
+    pub fn test_spawned_resolver_breaks_dead_lock() {
+        let lock_table = Arc::new(LockTable::new());
+        let resolver = lock_table.spawn_dead_lock_resolver(Duration::from_millis(5));
+        lock_mgr(&lock_table, "vs_t1", "vs_r1").acquire().unwrap();
+        lock_mgr(&lock_table, "vs_t2", "vs_r2").acquire().unwrap();
+        let first_join = {
+            let first_wait = lock_mgr(&lock_table, "vs_t1", "vs_r2");
+            std::thread::spawn(move || first_wait.acquire())
+        };
+        wait_for_waiters(&lock_table, "vs_r2", 1);
+        let second_join = {
+            let second_wait = lock_mgr(&lock_table, "vs_t2", "vs_r1");
+            std::thread::spawn(move || second_wait.acquire())
+        };
+
+        // Nobody calls resolve, the background thread has to break the cycle.
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while !(first_join.is_finished() && second_join.is_finished()) {
+            assert!(
+                std::time::Instant::now() < deadline,
+                "deadlock not resolved"
+            );
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        drop(resolver);
+        assert!(first_join.join().unwrap().is_ok());
+        assert!(matches!(
+            second_join.join().unwrap().unwrap_err().downcast_ref::<LockErrorCode>(),
+            Some(LockErrorCode::Deadlock(op, _)) if op == "vs_t2"
+        ));
+        lock_mgr(&lock_table, "vs_t1", "vs_r1").release().unwrap();
+        lock_mgr(&lock_table, "vs_t1", "vs_r2").release().unwrap();
+        assert_eq!((0, 0), lock_table.table_sizes());
+    }
+
+    #[test]
+    pub fn test_resolve_dead_lock_fewest_locks() {
+        let lock_table = Arc::new(LockTable::new());
+        lock_mgr(&lock_table, "vf_t1", "vf_r1").acquire().unwrap();
+        lock_mgr(&lock_table, "vf_t2", "vf_r2").acquire().unwrap();
+        lock_mgr(&lock_table, "vf_t2", "vf_r3").acquire().unwrap();
+        let (t1_join, t2_join) =
+            dead_lock_scenario(&lock_table, "vf_t1", "vf_t2", "vf_r1", "vf_r2");
+
+        let victims = lock_table.resolve_dead_locks_with(&VictimPolicy::FewestLocks);
+        assert_eq!(vec!["vf_t1".to_string()], victims);
+        assert!(t1_join.join().unwrap().is_err());
+        assert!(t2_join.join().unwrap().is_ok());
+        for rid in ["vf_r1", "vf_r2", "vf_r3"] {
+            lock_mgr(&lock_table, "vf_t2", rid).release().unwrap();
+        }
+    }
+
+    #[test]
+    pub fn test_resolve_stale_dead_lock() {
+        let lock_table = Arc::new(LockTable::new());
+        // A cycle no lock request is behind, aborting the victim unlinks nothing.
+        let detector = lock_table.dead_lock_detector();
+        detector.link_node("vs_t1", "vs_t2", &"vs_r2".into());
+        detector.link_node("vs_t2", "vs_t1", &"vs_r1".into());
+        assert_eq!(1, lock_table.resolve_dead_locks().len());
+        assert!(lock_table.detect_dead_locks().is_empty());
+    }
+
+    fn timed_lock_mgr(
+        lock_table: &Arc<LockTable>,
+        op_id: &str,
+        rid: &str,
+        timestamp: u128,
+        prevention: DeadLockPrevention,
+    ) -> LockManager {
+        let operation =
+            Operation::new(op_id.to_string(), rid.to_string(), Write).with_timestamp(timestamp);
+        LockManager::new(lock_table.clone(), operation).with_prevention(prevention)
+    }
+
+    #[test]
+    pub fn test_wait_die() {
+        let lock_table = Arc::new(LockTable::new());
+        let rid = "wait_die";
+        let old = timed_lock_mgr(&lock_table, "wd_old", rid, 1, DeadLockPrevention::WaitDie);
+        let young = timed_lock_mgr(&lock_table, "wd_young", rid, 2, DeadLockPrevention::WaitDie);
+        let young_other = timed_lock_mgr(
+            &lock_table,
+            "wd_young",
+            "wait_die_other",
+            2,
+            DeadLockPrevention::WaitDie,
+        );
+
+        // A younger requester dies right away and loses what it held.
+        old.acquire().unwrap();
+        young_other.acquire().unwrap();
+        assert!(matches!(
+            young.acquire().unwrap_err().downcast_ref::<LockErrorCode>(),
+            Some(LockErrorCode::WaitDieAbort(op, _)) if op == "wd_young"
+        ));
+        assert!(young_other.release().is_err());
+        old.release().unwrap();
+
+        // An older requester waits for the younger holder.
+        young.acquire().unwrap();
+        let old_join = std::thread::spawn(move || old.acquire().map(|_| old));
+        wait_for_waiters(&lock_table, rid, 1);
+        young.release().unwrap();
+        old_join.join().unwrap().unwrap().release().unwrap();
+    }
+
+    #[test]
+    pub fn test_refused_request_leaves_no_stats() {
+        let lock_table = Arc::new(LockTable::new());
+        let rid = "refused";
+        let has_stats = |op_id: &str| {
+            lock_table
+                .operation_shard(op_id)
+                .op_stats
+                .contains_key(op_id)
+        };
+        let old = timed_lock_mgr(
+            &lock_table,
+            "refused_old",
+            rid,
+            1,
+            DeadLockPrevention::WaitDie,
+        );
+        old.acquire().unwrap();
+        assert!(lock_mgr(&lock_table, "refused_no_wait", rid)
+            .try_acquire(Duration::ZERO)
+            .is_err());
+        assert!(!has_stats("refused_no_wait"));
+        let young = timed_lock_mgr(
+            &lock_table,
+            "refused_young",
+            rid,
+            2,
+            DeadLockPrevention::WaitDie,
+        );
+        assert!(matches!(
+            young.acquire().unwrap_err().downcast_ref::<LockErrorCode>(),
+            Some(LockErrorCode::WaitDieAbort(_, _))
+        ));
+        assert!(!has_stats("refused_young"));
+        old.release().unwrap();
+        assert!(!has_stats("refused_old"));
+        assert_eq!((0, 0), lock_table.table_sizes());
+    }
+
+    #[test]
+    pub fn test_wound_wait() {
+        let lock_table = Arc::new(LockTable::new());
+        let rid = "wound_wait";
+        let old = timed_lock_mgr(&lock_table, "ww_old", rid, 1, DeadLockPrevention::WoundWait);
+        let young = timed_lock_mgr(
+            &lock_table,
+            "ww_young",
+            rid,
+            2,
+            DeadLockPrevention::WoundWait,
+        );
+
+        // An older requester wounds the younger holder and takes the lock.
+        young.acquire().unwrap();
+        assert!(old.acquire().is_ok());
+        assert!(matches!(
+            young.release().unwrap_err().downcast_ref::<LockErrorCode>(),
+            Some(LockErrorCode::Wounded(op, _)) if op == "ww_young"
+        ));
+
+        // A younger requester waits for the older holder.
+        let young_join = std::thread::spawn(move || young.acquire().map(|_| young));
+        wait_for_waiters(&lock_table, rid, 1);
+        old.release().unwrap();
+        young_join.join().unwrap().unwrap().release().unwrap();
+    }
+
+    #[test]
+    pub fn test_wound_waiting_holder() {
+        let lock_table = Arc::new(LockTable::new());
+        let old_r1 = timed_lock_mgr(
+            &lock_table,
+            "wwh_old",
+            "wwh_r1",
+            1,
+            DeadLockPrevention::WoundWait,
+        );
+        let young_r1 = timed_lock_mgr(
+            &lock_table,
+            "wwh_young",
+            "wwh_r1",
+            2,
+            DeadLockPrevention::WoundWait,
+        );
+        let young_r2 = timed_lock_mgr(
+            &lock_table,
+            "wwh_young",
+            "wwh_r2",
+            2,
+            DeadLockPrevention::WoundWait,
+        );
+        let other_r2 = timed_lock_mgr(
+            &lock_table,
+            "wwh_other",
+            "wwh_r2",
+            0,
+            DeadLockPrevention::WoundWait,
+        );
+
+        // young holds r1 and is blocked on r2, the older op wounds it while it waits.
+        other_r2.acquire().unwrap();
+        young_r1.acquire().unwrap();
+        let young_join = std::thread::spawn(move || young_r2.acquire());
+        wait_for_waiters(&lock_table, "wwh_r2", 1);
+        assert!(old_r1.acquire().is_ok());
+        assert!(matches!(
+            young_join.join().unwrap().unwrap_err().downcast_ref::<LockErrorCode>(),
+            Some(LockErrorCode::Wounded(op, rid)) if op == "wwh_young" && rid == "wwh_r1"
+        ));
+        old_r1.release().unwrap();
+        other_r2.release().unwrap();
+    }
+
+    fn held_mode(
+        lock_table: &Arc<LockTable>,
+        op_id: &str,
+        rid: impl Into<ResourceId>,
+    ) -> Option<LockMode> {
+        lock_table
+            .resource_table(&rid.into())?
+            .locks()
+            .into_iter()
+            .find(|lock| lock.op_id == op_id)
+            .map(|lock| lock.lock_mode)
+    }
+
+    fn tuple_lock_mgr(
+        lock_table: &Arc<LockTable>,
+        segment: &Segment,
+        op_id: &str,
+        index: &[i32],
+        op_type: crate::operation::OpType,
+    ) -> LockManager {
+        let operation = Operation::for_tuple(
+            op_id.to_string(),
+            segment.tuple_key(index).unwrap(),
+            op_type,
+        );
+        LockManager::new(lock_table.clone(), operation)
+    }
+
+    #[test]
+    pub fn test_tuple_locks_each_index() {
+        let lock_table = Arc::new(LockTable::new());
+        let ints = (0..30).collect::<Vec<_>>();
+        let segment = Segment::from_ints(10, &ints, "idx_seg".to_string());
+        let reader = tuple_lock_mgr(&lock_table, &segment, "idx_r", &[9, 10, 11], Read);
+        assert_eq!("idx_seg/(9,10,11)", reader.acquire().unwrap().rid);
+        for index in segment.tuple_key(&[9, 10, 11]).unwrap().index_resources() {
+            assert_eq!(
+                Some(LockMode::Shared),
+                held_mode(&lock_table, "idx_r", index)
+            );
+        }
+        // Both chunks the tuple spans are intention locked.
+        assert_eq!((6, 1), lock_table.table_sizes());
+        assert!(tuple_lock_mgr(&lock_table, &segment, "idx_w", &[10], Write)
+            .try_acquire(Duration::ZERO)
+            .is_err());
+        reader.release().unwrap();
+        assert_eq!((0, 0), lock_table.table_sizes());
+    }
+
+    #[test]
+    pub fn test_intention_locks() {
+        let lock_table = Arc::new(LockTable::new());
+        let ints = (0..30).collect::<Vec<_>>();
+        let segment = Segment::from_ints(10, &ints, "mgl_seg".to_string());
+        let chunk_0 = segment.parent_resources(&[1]).unwrap()[1].clone();
+        let chunk_1 = segment.parent_resources(&[15]).unwrap()[1].clone();
+
+        let reader = tuple_lock_mgr(&lock_table, &segment, "mgl_r", &[1, 2], Read);
+        let writer = tuple_lock_mgr(&lock_table, &segment, "mgl_w", &[15], Write);
+        reader.acquire().unwrap();
+        writer.acquire().unwrap();
+        assert_eq!(
+            Some(LockMode::IntentionShared),
+            held_mode(&lock_table, "mgl_r", segment.segment_id())
+        );
+        assert_eq!(
+            Some(LockMode::IntentionShared),
+            held_mode(&lock_table, "mgl_r", &chunk_0)
+        );
+        assert_eq!(
+            Some(LockMode::IntentionExclusive),
+            held_mode(&lock_table, "mgl_w", segment.segment_id())
+        );
+        assert_eq!(
+            Some(LockMode::IntentionExclusive),
+            held_mode(&lock_table, "mgl_w", &chunk_1)
+        );
+
+        let segment_table = lock_table.resource_table(segment.segment_id()).unwrap();
+        assert_eq!(LockMode::IntentionExclusive, segment_table.granted_mode());
+        assert_eq!(
+            LockMode::IntentionShared,
+            segment_table.granted_mode_except("mgl_w")
+        );
+
+        // Reading a whole chunk only conflicts with the chunk being written.
+        let chunk_0_reader = LockManager::new(
+            lock_table.clone(),
+            Operation::new("mgl_c0".to_string(), chunk_0.clone(), Read),
+        );
+        assert!(chunk_0_reader.try_acquire(Duration::ZERO).is_ok());
+        chunk_0_reader.release().unwrap();
+        let chunk_1_reader = LockManager::new(
+            lock_table.clone(),
+            Operation::new("mgl_c1".to_string(), chunk_1.clone(), Read),
+        );
+        assert!(chunk_1_reader.try_acquire(Duration::ZERO).is_err());
+        let segment_writer = LockManager::new(
+            lock_table.clone(),
+            Operation::new("mgl_s".to_string(), segment.segment_id(), Write),
+        );
+        assert!(segment_writer.try_acquire(Duration::ZERO).is_err());
+
+        reader.release().unwrap();
+        writer.release().unwrap();
+        assert_eq!(None, held_mode(&lock_table, "mgl_r", segment.segment_id()));
+        assert_eq!(None, held_mode(&lock_table, "mgl_w", &chunk_1));
+        assert!(segment_writer.try_acquire(Duration::ZERO).is_ok());
+        segment_writer.release().unwrap();
+    }
+
+    #[test]
+    pub fn test_intention_locks_shared_by_children() {
+        let lock_table = Arc::new(LockTable::new());
+        let ints = (0..30).collect::<Vec<_>>();
+        let segment = Segment::from_ints(10, &ints, "mgl_shared_seg".to_string());
+        let chunk_0 = segment.parent_resources(&[1]).unwrap()[1].clone();
+
+        let first = tuple_lock_mgr(&lock_table, &segment, "mgl_t", &[1], Read);
+        let second = tuple_lock_mgr(&lock_table, &segment, "mgl_t", &[2], Read);
+        let third = tuple_lock_mgr(&lock_table, &segment, "mgl_t", &[3], Write);
+        first.acquire().unwrap();
+        second.acquire().unwrap();
+        // Writing under the same parents converts IS into IX in place.
+        third.acquire().unwrap();
+        assert_eq!(
+            Some(LockMode::IntentionExclusive),
+            held_mode(&lock_table, "mgl_t", &chunk_0)
+        );
+
+        first.release().unwrap();
+        second.release().unwrap();
+        assert_eq!(
+            Some(LockMode::IntentionExclusive),
+            held_mode(&lock_table, "mgl_t", segment.segment_id())
+        );
+        third.release().unwrap();
+        assert_eq!(None, held_mode(&lock_table, "mgl_t", segment.segment_id()));
+        assert_eq!(None, held_mode(&lock_table, "mgl_t", &chunk_0));
+    }
+
+    fn op_lock_mgr(
+        lock_table: &Arc<LockTable>,
+        op_id: &str,
+        rid: impl Into<ResourceId>,
+        op_type: crate::operation::OpType,
+    ) -> LockManager {
+        LockManager::new(
+            lock_table.clone(),
+            Operation::new(op_id.to_string(), rid, op_type),
+        )
+    }
+
+    #[test]
+    pub fn test_upgrade_waits_for_other_readers() {
+        let lock_table = Arc::new(LockTable::new());
+        let rid = "upgrade";
+        op_lock_mgr(&lock_table, "up_a", rid, Read)
+            .acquire()
+            .unwrap();
+        let reader_b = op_lock_mgr(&lock_table, "up_b", rid, Read);
+        reader_b.acquire().unwrap();
+
+        let upgrade = op_lock_mgr(&lock_table, "up_a", rid, Write);
+        let upgrade_join = std::thread::spawn(move || upgrade.acquire());
+        wait_for_waiters(&lock_table, rid, 1);
+        assert_eq!(Some(LockMode::Shared), held_mode(&lock_table, "up_a", rid));
+        // Later readers queue up behind the pending upgrade.
+        let reader_c = op_lock_mgr(&lock_table, "up_c", rid, Read);
+        assert!(reader_c.try_acquire(Duration::ZERO).is_err());
+        // Re-reading the resource does not wait for its own upgrade.
+        let reread = op_lock_mgr(&lock_table, "up_a", rid, Read);
+        assert_eq!(LockMode::Shared, reread.acquire().unwrap().lock_mode);
+
+        reader_b.release().unwrap();
+        let upgraded = upgrade_join.join().unwrap().unwrap();
+        assert_eq!(LockMode::Exclusive, upgraded.lock_mode);
+        assert_eq!(
+            Some(LockMode::Exclusive),
+            held_mode(&lock_table, "up_a", rid)
+        );
+        assert_eq!(
+            1,
+            lock_table.resource_table(&rid.into()).unwrap().lock_size()
+        );
+        // Read, re-read and upgrade are three acquisitions, released one by one.
+        reread.release().unwrap();
+        op_lock_mgr(&lock_table, "up_a", rid, Read)
+            .release()
+            .unwrap();
+        assert_eq!(
+            Some(LockMode::Exclusive),
+            held_mode(&lock_table, "up_a", rid)
+        );
+        op_lock_mgr(&lock_table, "up_a", rid, Write)
+            .release()
+            .unwrap();
+        assert_eq!((0, 0), lock_table.table_sizes());
+    }
+
+    #[test]
+    pub fn test_failed_path_restores_upgrades() {
+        let lock_table = Arc::new(LockTable::new());
+        let ints = (0..30).collect::<Vec<_>>();
+        let segment = Segment::from_ints(10, &ints, "restore_seg".to_string());
+        let chunk = segment.segment_id().chunk(0, 10);
+        let reader = tuple_lock_mgr(&lock_table, &segment, "restore_a", &[1], Read);
+        reader.acquire().unwrap();
+        let other = tuple_lock_mgr(&lock_table, &segment, "restore_b", &[2], Read);
+        other.acquire().unwrap();
+
+        // The intention locks are converted to IX before the tuple conflicts.
+        let writer = tuple_lock_mgr(&lock_table, &segment, "restore_a", &[2], Write);
+        assert!(writer.try_acquire(Duration::ZERO).is_err());
+        for rid in [segment.segment_id().clone(), chunk.clone()] {
+            assert_eq!(
+                Some(LockMode::IntentionShared),
+                held_mode(&lock_table, "restore_a", rid)
+            );
+        }
+        // So the chunk can still be read as a whole.
+        let chunk_reader = op_lock_mgr(&lock_table, "restore_c", chunk, Read);
+        chunk_reader.try_acquire(Duration::ZERO).unwrap();
+        chunk_reader.release().unwrap();
+        reader.release().unwrap();
+        other.release().unwrap();
+        assert_eq!((0, 0), lock_table.table_sizes());
+    }
+
+    #[test]
+    pub fn test_reentrant_lock() {
+        let lock_table = Arc::new(LockTable::new());
+        let rid = "reentrant";
+        let writer = op_lock_mgr(&lock_table, "re_a", rid, Write);
+        writer.acquire().unwrap();
+        // Held as strongly or more strongly, the lock is granted without waiting.
+        writer.try_acquire(Duration::ZERO).unwrap();
+        let reader = op_lock_mgr(&lock_table, "re_a", rid, Read);
+        assert_eq!(LockMode::Exclusive, reader.acquire().unwrap().lock_mode);
+        let other = op_lock_mgr(&lock_table, "re_b", rid, Read);
+        assert!(other.try_acquire(Duration::ZERO).is_err());
+
+        reader.release().unwrap();
+        writer.release().unwrap();
+        assert!(other.try_acquire(Duration::ZERO).is_err());
+        writer.release().unwrap();
+        other.acquire().unwrap();
+        assert!(matches!(
+            writer
+                .release()
+                .unwrap_err()
+                .downcast_ref::<LockErrorCode>(),
+            Some(LockErrorCode::NoLockHeld(_))
+        ));
+        other.release().unwrap();
+        assert_eq!((0, 0), lock_table.table_sizes());
+    }
+
+    #[test]
+    pub fn test_reentrant_intention_lock() {
+        let lock_table = Arc::new(LockTable::new());
+        let ints = (0..30).collect::<Vec<_>>();
+        let segment = Segment::from_ints(10, &ints, "ri_seg".to_string());
+        let chunk = segment.parent_resources(&[1]).unwrap()[1].clone();
+        let tuple = tuple_lock_mgr(&lock_table, &segment, "ri_a", &[1], Read);
+        tuple.acquire().unwrap();
+        // Reading the whole chunk as well turns the implicit IS lock into S.
+        let chunk_reader = op_lock_mgr(&lock_table, "ri_a", &chunk, Read);
+        chunk_reader.acquire().unwrap();
+        tuple.release().unwrap();
+        assert_eq!(
+            Some(LockMode::Shared),
+            held_mode(&lock_table, "ri_a", &chunk)
+        );
+        chunk_reader.release().unwrap();
+        assert_eq!((0, 0), lock_table.table_sizes());
+    }
+
+    #[test]
+    pub fn test_upgrade_deadlock() {
+        let lock_table = Arc::new(LockTable::new());
+        let rid = "upgrade_dl";
+        op_lock_mgr(&lock_table, "udl_a", rid, Read)
+            .acquire()
+            .unwrap();
+        op_lock_mgr(&lock_table, "udl_b", rid, Read)
+            .acquire()
+            .unwrap();
+
+        let upgrade_a = op_lock_mgr(&lock_table, "udl_a", rid, Write);
+        let upgrade_join = std::thread::spawn(move || upgrade_a.acquire());
+        wait_for_waiters(&lock_table, rid, 1);
+        let upgrade_b = op_lock_mgr(&lock_table, "udl_b", rid, Write);
+        assert!(matches!(
+            upgrade_b.acquire().unwrap_err().downcast_ref::<LockErrorCode>(),
+            Some(LockErrorCode::UpgradeDeadlock(op, _)) if op == "udl_b"
+        ));
+        assert_eq!(Some(LockMode::Shared), held_mode(&lock_table, "udl_b", rid));
+
+        upgrade_b.release().unwrap();
+        assert!(upgrade_join.join().unwrap().is_ok());
+        assert!(lock_table.detect_dead_locks().is_empty());
+        op_lock_mgr(&lock_table, "udl_a", rid, Write)
+            .release()
+            .unwrap();
+    }
+
+    #[test]
+    pub fn test_downgrade_wakes_readers() {
+        let lock_table = Arc::new(LockTable::new());
+        let rid = "downgrade";
+        let writer = op_lock_mgr(&lock_table, "dg_x", rid, Write);
+        writer.acquire().unwrap();
+
+        let readers = ["dg_s1", "dg_s2"]
+            .into_iter()
+            .enumerate()
+            .map(|(idx, op_id)| {
+                let reader = op_lock_mgr(&lock_table, op_id, rid, Read);
+                let join = std::thread::spawn(move || reader.acquire().map(|_| reader));
+                wait_for_waiters(&lock_table, rid, idx + 1);
+                join
+            })
+            .collect::<Vec<_>>();
+        let late_writer = op_lock_mgr(&lock_table, "dg_x2", rid, Write);
+        let late_join = std::thread::spawn(move || late_writer.acquire().map(|_| late_writer));
+        wait_for_waiters(&lock_table, rid, 3);
+
+        assert_eq!(LockMode::Shared, writer.downgrade().unwrap().lock_mode);
+        assert_eq!(Some(LockMode::Shared), held_mode(&lock_table, "dg_x", rid));
+        for reader in readers {
+            reader.join().unwrap().unwrap().release().unwrap();
+        }
+        // The writer behind them still waits for the downgraded lock.
+        assert_eq!(1, waiters_size(&lock_table, rid));
+        assert!(matches!(
+            writer
+                .downgrade()
+                .unwrap_err()
+                .downcast_ref::<LockErrorCode>(),
+            Some(LockErrorCode::InvalidDowngrade(_, _))
+        ));
+        writer.release().unwrap();
+        late_join.join().unwrap().unwrap().release().unwrap();
+        assert!(writer.downgrade().is_err());
+    }
+
+    #[test]
+    pub fn test_downgrade_refuses_six() {
+        let lock_table = Arc::new(LockTable::new());
+        let chunk = ResourceId::segment("dg_seg").chunk(0, 10);
+        let chunk_reader = op_lock_mgr(&lock_table, "dg_six", chunk.clone(), Read);
+        chunk_reader.acquire().unwrap();
+        let child_writer = op_lock_mgr(&lock_table, "dg_six", chunk.tuple(3), Write);
+        child_writer.acquire().unwrap();
+        assert_eq!(
+            Some(LockMode::SharedIntentionExclusive),
+            held_mode(&lock_table, "dg_six", chunk.clone())
+        );
+        assert!(matches!(
+            chunk_reader
+                .downgrade()
+                .unwrap_err()
+                .downcast_ref::<LockErrorCode>(),
+            Some(LockErrorCode::InvalidDowngrade(_, _))
+        ));
+        // Readers of the whole chunk still wait for the written child.
+        assert!(op_lock_mgr(&lock_table, "dg_chunk_r", chunk.clone(), Read)
+            .try_acquire(Duration::ZERO)
+            .is_err());
+        child_writer.release().unwrap();
+        chunk_reader.release().unwrap();
+        assert_eq!((0, 0), lock_table.table_sizes());
+    }
+
+    #[test]
+    pub fn test_update_lock_avoids_upgrade_deadlock() {
+        let lock_table = Arc::new(LockTable::new());
+        let rid = "update";
+        op_lock_mgr(&lock_table, "upd_a", rid, ReadForUpdate)
+            .acquire()
+            .unwrap();
+        let reader = op_lock_mgr(&lock_table, "upd_r", rid, Read);
+        assert!(reader.try_acquire(Duration::ZERO).is_ok());
+        // The second updater waits right away instead of later upgrading into a deadlock.
+        let updater_b = op_lock_mgr(&lock_table, "upd_b", rid, ReadForUpdate);
+        let updater_join = std::thread::spawn(move || updater_b.acquire().map(|_| updater_b));
+        wait_for_waiters(&lock_table, rid, 1);
+
+        let writer_a = op_lock_mgr(&lock_table, "upd_a", rid, Write);
+        let writer_join = std::thread::spawn(move || writer_a.acquire().map(|_| writer_a));
+        wait_for_waiters(&lock_table, rid, 2);
+        reader.release().unwrap();
+        let writer_a = writer_join.join().unwrap().unwrap();
+        assert_eq!(
+            Some(LockMode::Exclusive),
+            held_mode(&lock_table, "upd_a", rid)
+        );
+        writer_a.release().unwrap();
+        op_lock_mgr(&lock_table, "upd_a", rid, ReadForUpdate)
+            .release()
+            .unwrap();
+        updater_join.join().unwrap().unwrap().release().unwrap();
+        assert!(lock_table.detect_dead_locks().is_empty());
+    }
+
+    #[test]
+    pub fn test_increment_locks_share() {
+        let lock_table = Arc::new(LockTable::new());
+        let rid = "counter";
+        let first = op_lock_mgr(&lock_table, "inc_1", rid, Increment);
+        let second = op_lock_mgr(&lock_table, "inc_2", rid, Increment);
+        assert!(first.try_acquire(Duration::ZERO).is_ok());
+        assert!(second.try_acquire(Duration::ZERO).is_ok());
+        let reader = op_lock_mgr(&lock_table, "inc_r", rid, Read);
+        assert!(reader.try_acquire(Duration::ZERO).is_err());
+        first.release().unwrap();
+        second.release().unwrap();
+        assert!(reader.try_acquire(Duration::ZERO).is_ok());
+        reader.release().unwrap();
+    }
 }
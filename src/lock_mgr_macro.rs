@@ -6,6 +6,10 @@ macro_rules! declare_locks_table {
         pub(crate) struct $struct_name {
             table_key: $field_name,
             locks: std::sync::Arc<parking_lot::RwLock<Vec<Lock>>>,
+            /// Requests blocked on this table in arrival order.
+            wait_queue: std::sync::Arc<
+                parking_lot::Mutex<std::collections::VecDeque<$crate::wait_queue::LockRequest>>,
+            >,
         }
 
         impl $struct_name {
@@ -13,18 +17,37 @@ macro_rules! declare_locks_table {
                 Self {
                     table_key: input_arg,
                     locks: std::sync::Arc::new(parking_lot::RwLock::new(vec![])),
+                    wait_queue: std::sync::Arc::new(parking_lot::Mutex::new(
+                        std::collections::VecDeque::new(),
+                    )),
                 }
             }
 
+            /// The granted-group mode of every lock held, see `LockMode::group_mode`.
+            pub fn granted_mode(&self) -> LockMode {
+                LockMode::group_mode(self.locks.read().iter().map(|lock| lock.lock_mode))
+            }
+
+            /// The granted-group mode of the locks held by operations other than `op_id`.
+            pub fn granted_mode_except(&self, op_id: &str) -> LockMode {
+                LockMode::group_mode(
+                    self.locks
+                        .read()
+                        .iter()
+                        .filter(|lock| lock.op_id != op_id)
+                        .map(|lock| lock.lock_mode),
+                )
+            }
+
             /// check resource held lock conflict
             pub fn lock_conflicts(&self, require_lock: LockMode) -> bool {
-                let locks_vec = &*self.locks.read();
-                for lock in locks_vec.iter() {
-                    if !lock.lock_mode.compatible(require_lock) {
-                        return true;
-                    }
-                }
-                false
+                !self.granted_mode().compatible(require_lock)
+            }
+
+            /// Like `lock_conflicts` but ignoring the lock held by `op_id` itself, used when
+            /// an operation converts its own lock.
+            pub fn lock_conflicts_except(&self, require_lock: LockMode, op_id: &str) -> bool {
+                !self.granted_mode_except(op_id).compatible(require_lock)
             }
 
             pub fn lock_size(&self) -> usize {
@@ -38,6 +61,30 @@ macro_rules! declare_locks_table {
                 vec_lock.retain(|lock| lock.rid != input_rid);
             }
 
+            pub fn has_lock(&self, input_rid: &ResourceId) -> bool {
+                self.locks.read().iter().any(|lock| &lock.rid == input_rid)
+            }
+
+            pub fn locks(&self) -> Vec<Lock> {
+                self.locks.read().clone()
+            }
+
+            pub fn remove_holder(&self, op_id: &str) {
+                let mut lock_vec = self.locks.write();
+                lock_vec.retain(|lock| lock.op_id != op_id);
+            }
+
+            /// Replace the lock held by the operation of `new_lock`.
+            pub fn update_holder(&self, new_lock: Lock) {
+                let locks_vec = &mut *self.locks.write();
+                for lock in locks_vec.iter_mut() {
+                    if lock.op_id == new_lock.op_id {
+                        *lock = new_lock;
+                        return;
+                    }
+                }
+            }
+
             pub fn update_lock(&self, new_lock: Lock, input_rid: ResourceId) {
                 let locks_vec = &mut *self.locks.write();
                 let mut replace_id = 0_i32;
@@ -67,6 +114,71 @@ macro_rules! declare_locks_table {
                 }
                 LockMode::NoLock
             }
+
+            /// Queue `request`, an upgrade goes ahead of every other request as it only
+            /// waits for the other holders.
+            pub fn enqueue(&self, request: $crate::wait_queue::LockRequest) {
+                if request.upgrade {
+                    self.wait_queue.lock().push_front(request);
+                } else {
+                    self.wait_queue.lock().push_back(request);
+                }
+            }
+
+            /// Whether an upgrade is pending, there is at most one per resource.
+            pub fn has_upgrade(&self) -> bool {
+                self.wait_queue
+                    .lock()
+                    .front()
+                    .map(|req| req.upgrade)
+                    .unwrap_or(false)
+            }
+
+            pub fn waiting_locks(&self) -> Vec<Lock> {
+                self.wait_queue
+                    .lock()
+                    .iter()
+                    .map(|req| req.lock.clone())
+                    .collect()
+            }
+
+            pub fn has_waiters(&self) -> bool {
+                !self.wait_queue.lock().is_empty()
+            }
+
+            pub fn waiters_size(&self) -> usize {
+                self.wait_queue.lock().len()
+            }
+
+            /// Take a request out of the queue, e.g. when its wait timed out.
+            pub fn remove_waiter(&self, op_id: &str) -> Option<$crate::wait_queue::LockRequest> {
+                let mut queue = self.wait_queue.lock();
+                let pos = queue.iter().position(|req| req.lock.op_id == op_id)?;
+                queue.remove(pos)
+            }
+
+            /// Grant the head of the queue if it is compatible with every holder, an upgrade
+            /// if it is compatible with every other holder. Only the head is ever considered
+            /// so later requests can not overtake earlier ones.
+            pub fn pop_grantable(&self) -> Option<$crate::wait_queue::LockRequest> {
+                let mut queue = self.wait_queue.lock();
+                let head = queue.front()?;
+                let conflicts = if head.upgrade {
+                    self.lock_conflicts_except(head.lock.lock_mode, &head.lock.op_id)
+                } else {
+                    self.lock_conflicts(head.lock.lock_mode)
+                };
+                if conflicts {
+                    return None;
+                }
+                let request = queue.pop_front()?;
+                if request.upgrade {
+                    self.update_holder(request.lock.clone());
+                } else {
+                    self.add_lock(request.lock.clone());
+                }
+                Some(request)
+            }
         }
     };
 }
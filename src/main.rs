@@ -1,16 +1,65 @@
+use r_tpl::lock_mgr::LockTable;
 use r_tpl::operation_scheduler::OperationScheduler;
 use r_tpl::segment::Segment;
+use r_tpl::segment_store::SegmentStore;
+use r_tpl::value::Value;
+use std::path::Path;
 use std::sync::Arc;
+use std::time::Instant;
 
 const DATA_SIZE: i32 = 100000;
 const WORKER_NUM: i32 = 4;
 
+/// The dataset to run on: the snapshot given as first argument when it exists, otherwise
+/// freshly generated and saved there for the next runs. Generated values are ints, or
+/// byte payloads of the size given as second argument.
+fn dataset() -> Segment {
+    let snapshot = std::env::args().nth(1);
+    if let Some(path) = snapshot.as_deref().filter(|path| Path::new(path).exists()) {
+        let segment = Segment::load(path).expect("load snapshot");
+        println!("Loaded dataset from {}", path);
+        return segment;
+    }
+    let payload_size = std::env::args()
+        .nth(2)
+        .map(|size| size.parse::<usize>().expect("payload size"));
+    let values = (1..=DATA_SIZE)
+        .map(|int| match payload_size {
+            Some(size) => Value::Bytes(vec![int as u8; size]),
+            None => Value::from(int),
+        })
+        .collect::<Vec<_>>();
+    let segment = Segment::from_values(10000, &values, "DefaultSegmentId".to_string());
+    if let Some(path) = snapshot {
+        segment.save(&path).expect("save snapshot");
+        println!("Saved dataset to {}", path);
+    }
+    segment
+}
+
+/// Run the workload on `segment` with locks in `lock_table` and return its ops/s.
+async fn run(label: &str, lock_table: LockTable, segment: Segment) -> f64 {
+    let start = Instant::now();
+    let op_count = OperationScheduler::schedule_with_task(
+        Arc::new(lock_table),
+        Arc::new(SegmentStore::new(segment)),
+        WORKER_NUM,
+    )
+    .await;
+    let elapsed = start.elapsed();
+    let throughput = op_count as f64 / elapsed.as_secs_f64();
+    println!(
+        "{}: scheduled {} operations in {:?}, {:.0} ops/s",
+        label, op_count, elapsed, throughput
+    );
+    throughput
+}
+
 #[tokio::main(flavor = "multi_thread", worker_threads = 6)]
 async fn main() {
-    let ints: [i32; DATA_SIZE as usize] = (1..=DATA_SIZE)
-        .collect::<Vec<_>>()
-        .try_into()
-        .expect("size error iter");
-    let segment = Segment::from_ints(10000, &ints, "DefaultSegmentId".to_string());
-    OperationScheduler::schedule_with_task(Arc::new(segment), WORKER_NUM).await;
+    let segment = dataset();
+    // One bucket latches the whole table for every request, as before sharding.
+    let baseline = run("1 bucket", LockTable::new().with_shards(1), segment.clone()).await;
+    let sharded = run("sharded", LockTable::new(), segment).await;
+    println!("Sharding speedup {:.2}x", sharded / baseline);
 }
@@ -1,23 +1,42 @@
-use crate::segment::ResourceId;
+use crate::segment::{RangeKey, ResourceId, TupleKey};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-#[derive(Eq, PartialEq, Hash, Debug, Clone)]
+/// Nanoseconds since the unix epoch, used both for operation ids and timestamps.
+pub fn timestamp_nanos() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos()
+}
+
+#[derive(Eq, PartialEq, Hash, Debug, Clone, Default)]
 pub enum OpType {
     Read,
     Write,
+    /// Read a resource that is going to be written, so no other reader can plan the same.
+    ReadForUpdate,
+    /// Commutative update like adding to a counter.
+    Increment,
+    #[default]
     NoOp,
 }
 
-impl Default for OpType {
-    fn default() -> Self {
-        OpType::NoOp
-    }
-}
-
 #[derive(Debug, Hash, Eq, PartialEq, Clone)]
 pub struct Operation {
     pub op_type: OpType,
     pub id: String,
     pub resources: ResourceId,
+    /// Start time of the transaction the operation belongs to, a smaller timestamp is
+    /// older. Used by the Wait-Die and Wound-Wait deadlock prevention schemes.
+    pub timestamp: u128,
+    /// Ancestors of `resources` from the root down, e.g. segment then chunk. They are
+    /// locked in intention mode before `resources` itself.
+    pub parents: Vec<ResourceId>,
+    /// Set for an operation on a tuple, whose indices are locked instead of `resources`.
+    pub tuple: Option<TupleKey>,
+    /// Set for an operation on an index range, whose chunks and indices are locked
+    /// instead of `resources`.
+    pub range: Option<RangeKey>,
 }
 
 impl Default for Operation {
@@ -25,17 +44,73 @@ impl Default for Operation {
         Self {
             op_type: OpType::NoOp,
             id: "_NONE".to_string(),
-            resources: "_NONE_RID".to_string(),
+            resources: ResourceId::named("_NONE_RID"),
+            timestamp: 0,
+            parents: vec![],
+            tuple: None,
+            range: None,
         }
     }
 }
 
 impl Operation {
-    pub fn new(id: String, rid: ResourceId, op_type: OpType) -> Self {
+    /// An operation on `rid`, below the intention locks of the ancestors of `rid`.
+    pub fn new(id: String, rid: impl Into<ResourceId>, op_type: OpType) -> Self {
+        let rid = rid.into();
         Self {
             op_type,
             id,
+            parents: rid.ancestors(),
             resources: rid,
+            timestamp: timestamp_nanos(),
+            tuple: None,
+            range: None,
         }
     }
+
+    /// An operation on the tuple `key`: each of its indices is locked, below the intention
+    /// locks of the segment and chunks. `resources` names the whole tuple.
+    pub fn for_tuple(id: String, key: TupleKey, op_type: OpType) -> Self {
+        Self::new(id, ResourceId::named(key.to_string()), op_type)
+            .with_parents(key.parents())
+            .with_tuple(key)
+    }
+
+    pub fn with_tuple(mut self, key: TupleKey) -> Self {
+        self.tuple = Some(key);
+        self
+    }
+
+    /// An operation on the index range `key`, see `RangeKey` for what gets locked.
+    /// `resources` names the whole range.
+    pub fn for_range(id: String, key: RangeKey, op_type: OpType) -> Self {
+        Self::new(id, ResourceId::named(key.to_string()), op_type)
+            .with_parents(key.parents())
+            .with_range(key)
+    }
+
+    pub fn with_range(mut self, key: RangeKey) -> Self {
+        self.range = Some(key);
+        self
+    }
+
+    /// The resources locked in the requested mode: every index of the tuple, the chunks
+    /// and indices of the range, or else `resources` itself.
+    pub fn targets(&self) -> Vec<ResourceId> {
+        match (&self.tuple, &self.range) {
+            (Some(key), _) => key.index_resources(),
+            (None, Some(key)) => key.resources(),
+            (None, None) => vec![self.resources.clone()],
+        }
+    }
+
+    pub fn with_parents(mut self, parents: Vec<ResourceId>) -> Self {
+        self.parents = parents;
+        self
+    }
+
+    pub fn with_timestamp(mut self, timestamp: u128) -> Self {
+        self.timestamp = timestamp;
+        self
+    }
 }
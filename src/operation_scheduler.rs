@@ -1,9 +1,11 @@
-use crate::lock_mgr::LockManager;
-use crate::operation::{OpType, Operation};
-use crate::segment::{Segment, Tuple};
+use crate::lock_mgr::{DeadLockPrevention, LockTable};
+use crate::operation::timestamp_nanos;
+use crate::segment_store::SegmentStore;
+use crate::transaction::{Transaction, TransactionState};
+use crate::value::Value;
+use anyhow::Result;
 use rand::Rng;
 use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
 
 #[derive(Debug, Clone, Eq, PartialEq, Default)]
 pub struct OperationScheduler;
@@ -12,75 +14,100 @@ const OPERATION_COUNT: i32 = 10000_i32;
 
 impl OperationScheduler {
     pub fn op_id() -> String {
-        let time = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_nanos();
-        time.to_string()
+        timestamp_nanos().to_string()
     }
 
+    /// Indices `[i, i+1, i+2, j]` below `segment_capacity`, wrapping around at the end.
     pub fn rand_index(segment_capacity: i32) -> Vec<i32> {
         let mut value_index = vec![];
         let rand_i = rand::thread_rng().gen_range(0_i32..segment_capacity);
         value_index.push(rand_i);
         for idx in 1..3 {
-            if rand_i + idx > segment_capacity {
-                value_index.push(segment_capacity % (rand_i + idx))
-            } else {
-                value_index.push(rand_i + idx)
-            }
+            value_index.push((rand_i + idx) % segment_capacity)
         }
         let rand_j = rand::thread_rng().gen_range(1_i32..segment_capacity);
         value_index.push(rand_j);
         value_index
     }
 
-    pub async fn schedule_with_task(segment: Arc<Segment>, worker_size: i32) {
-        let segment_capacity = segment.capacity();
+    /// Run `worker_size` workers each running transactions over random tuples of `store`
+    /// with locks of `lock_table`, and return the number of reads and writes of the
+    /// committed ones. A transaction aborted by a lock conflict (Wait-Die) or as deadlock
+    /// victim is retried with its original timestamp, so it gets older until it wins.
+    pub async fn schedule_with_task(
+        lock_table: Arc<LockTable>,
+        store: Arc<SegmentStore>,
+        worker_size: i32,
+    ) -> usize {
         let mut join_handlers = vec![];
         for worker_num in 0..worker_size {
-            let join_handler = tokio::task::spawn(async move {
-                println!("curr thread = {:?}", std::thread::current().id());
+            let store = store.clone();
+            let lock_table = lock_table.clone();
+            let join_handler = tokio::spawn(async move {
+                let mut completed = 0_usize;
                 for _op_count in 0..OPERATION_COUNT {
-                    let ops = OperationScheduler::new_operation(
-                        segment_capacity,
-                        (
+                    let value_index = OperationScheduler::rand_index(store.segment().capacity());
+                    let timestamp = timestamp_nanos();
+                    loop {
+                        let mut txn = Transaction::new(
+                            lock_table.clone(),
                             format!("{}/{}", OperationScheduler::op_id(), worker_num),
-                            format!("{}/{}", OperationScheduler::op_id(), worker_num),
-                        ),
-                    );
-                    let read_op = ops.0;
-                    let write_op = ops.1;
-
-                    let read_lock_mgr = LockManager::new(read_op);
-                    let write_lock_mgr = LockManager::new(write_op);
-
-                    let read_lock = read_lock_mgr.acquire();
-                    let write_lock = write_lock_mgr.acquire();
-                    println!("Acquire S_Lock = {:?},X_Lock = {:?}", read_lock, write_lock);
-                    let release_rs = read_lock_mgr.release();
-                    let write_rs = write_lock_mgr.release();
-                    println!("Release S_Lock = {:?},X_Lock = {:?}", release_rs, write_rs);
+                        )
+                        .with_timestamp(timestamp)
+                        .with_prevention(DeadLockPrevention::WaitDie);
+                        match OperationScheduler::run_transaction(&store, &mut txn, &value_index)
+                            .await
+                        {
+                            Ok(op_count) => {
+                                completed += op_count;
+                                break;
+                            }
+                            Err(_) if txn.state() == TransactionState::Aborted => {
+                                // Let the transaction that won the conflict go on first.
+                                tokio::task::yield_now().await;
+                            }
+                            Err(_) => {
+                                let _ = txn.abort();
+                                break;
+                            }
+                        }
+                    }
                 }
+                completed
             });
             join_handlers.push(join_handler);
         }
+        let mut completed = 0_usize;
         for join_wait in join_handlers {
-            let _ = join_wait.await;
+            completed += join_wait.await.unwrap_or_default();
         }
+        completed
     }
 
-    pub fn new_operation(
-        segment_capacity: i32,
-        op_id_pair: (String, String),
-    ) -> (Operation, Operation) {
-        // [i,i+1,i+2,j]
-        let value_index = OperationScheduler::rand_index(segment_capacity);
-        let read_tuple = Tuple::empty_tuple(&value_index[0..3]);
-        let write_tuple = Tuple::empty_tuple(&[(value_index.len() - 1).try_into().unwrap()]);
-        (
-            Operation::new(op_id_pair.0, read_tuple.tuple_id, OpType::Read),
-            Operation::new(op_id_pair.1, write_tuple.tuple_id, OpType::Write),
-        )
+    /// Read the values at `[i, i+1, i+2]` and write their sum to `j`, or a copy of the
+    /// value at `i` when they are not all ints, then commit. Returns the number of reads
+    /// and writes.
+    pub async fn run_transaction(
+        store: &SegmentStore,
+        txn: &mut Transaction,
+        value_index: &[i32],
+    ) -> Result<usize> {
+        let (write_index, read_index) = value_index.split_last().unwrap();
+        let mut values = vec![];
+        for index in read_index {
+            values.push(store.read_async(txn, *index as usize).await?);
+        }
+        let sum = values
+            .iter()
+            .try_fold(0_i64, |sum, value| Some(sum.wrapping_add(value.as_int()?)));
+        let new_value = match sum {
+            Some(sum) => Value::Int(sum),
+            None => values.swap_remove(0),
+        };
+        store
+            .write_async(txn, *write_index as usize, new_value)
+            .await?;
+        txn.commit()?;
+        Ok(value_index.len())
     }
 }
@@ -1,25 +1,394 @@
-pub type ResourceId = String;
+use crate::codec::{crc32, Decoder, Encoder};
+use crate::value::Value;
+use anyhow::{anyhow, Result};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Display, Formatter, Write};
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::Write as _;
+use std::path::Path;
+use std::sync::Arc;
+use thiserror::Error;
+
 pub type IndexRange = (usize, usize);
 
+/// `RTPS`, the first bytes of a segment snapshot.
+const SNAPSHOT_MAGIC: u32 = 0x5350_5452;
+const SNAPSHOT_VERSION: u32 = 2;
+
+#[derive(Error, Debug, Clone, Eq, PartialEq)]
+pub enum SegmentError {
+    #[error("Index {0} is outside of segment RES_ID {1}")]
+    IndexOutOfRange(i64, ResourceId),
+    #[error("Empty range [{0}, {1}) of segment RES_ID {2}")]
+    EmptyRange(usize, usize, ResourceId),
+}
+
+#[derive(Error, Debug, Clone, Eq, PartialEq)]
+pub enum SnapshotErrorCode {
+    #[error("Snapshot of {0} bytes is too short")]
+    Truncated(usize),
+    #[error("Snapshot checksum {0:#010x} does not match its content {1:#010x}")]
+    ChecksumMismatch(u32, u32),
+    #[error("Not a segment snapshot, magic {0:#010x}")]
+    BadMagic(u32),
+    #[error("Unsupported snapshot version {0}")]
+    UnsupportedVersion(u32),
+    #[error("Unexpected bytes after the last chunk of the snapshot")]
+    TrailingBytes,
+    #[error("Snapshot of {0} values in chunks of {1} has {2} chunks")]
+    ChunkCount(usize, usize, usize),
+    #[error("Chunk {0} of the snapshot is not [{1}, {2})")]
+    ChunkLayout(usize, usize, usize),
+}
+
+/// Where a resource sits in the lock hierarchy segment → chunk → tuple.
+#[derive(Debug, Clone, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub enum ResourceKind {
+    /// A resource outside of any segment.
+    Named(Box<str>),
+    Segment(Box<str>),
+    /// The indices `[start, end)` of the parent segment.
+    Chunk(IndexRange),
+    /// A single index of the parent chunk.
+    Tuple(usize),
+}
+
+/// Id of a lockable resource. Segments, chunks and tuples know their parent, so the lock
+/// manager walks up from a tuple for the intention locks of its chunk and segment. Ids
+/// are shared behind an `Arc` with their hash computed once, cloning, hashing and
+/// comparing one never copies or rereads the names.
+#[derive(Clone)]
+pub struct ResourceId(Arc<ResourceNode>);
+
+struct ResourceNode {
+    kind: ResourceKind,
+    parent: Option<ResourceId>,
+    hash: u64,
+}
+
+impl ResourceId {
+    pub fn named(name: impl Into<String>) -> Self {
+        Self::with_parent(ResourceKind::Named(name.into().into_boxed_str()), None)
+    }
+
+    pub fn segment(name: impl Into<String>) -> Self {
+        Self::with_parent(ResourceKind::Segment(name.into().into_boxed_str()), None)
+    }
+
+    /// The chunk `[start, end)` of this segment.
+    pub fn chunk(&self, start: usize, end: usize) -> Self {
+        Self::with_parent(ResourceKind::Chunk((start, end)), Some(self.clone()))
+    }
+
+    /// The tuple at `index` of this chunk.
+    pub fn tuple(&self, index: usize) -> Self {
+        Self::with_parent(ResourceKind::Tuple(index), Some(self.clone()))
+    }
+
+    fn with_parent(kind: ResourceKind, parent: Option<ResourceId>) -> Self {
+        let mut hasher = DefaultHasher::new();
+        kind.hash(&mut hasher);
+        parent.hash(&mut hasher);
+        Self(Arc::new(ResourceNode {
+            kind,
+            parent,
+            hash: hasher.finish(),
+        }))
+    }
+
+    pub fn kind(&self) -> &ResourceKind {
+        &self.0.kind
+    }
+
+    pub fn parent(&self) -> Option<&ResourceId> {
+        self.0.parent.as_ref()
+    }
+
+    /// Every resource above this one, from the root down.
+    pub fn ancestors(&self) -> Vec<ResourceId> {
+        let mut ancestors = vec![];
+        let mut parent = self.parent();
+        while let Some(rid) = parent {
+            ancestors.push(rid.clone());
+            parent = rid.parent();
+        }
+        ancestors.reverse();
+        ancestors
+    }
+
+    /// The segment this resource belongs to, itself for a segment.
+    pub fn segment_of(&self) -> Option<&ResourceId> {
+        match self.kind() {
+            ResourceKind::Segment(_) => Some(self),
+            ResourceKind::Named(_) => None,
+            ResourceKind::Chunk(_) | ResourceKind::Tuple(_) => self.parent()?.segment_of(),
+        }
+    }
+
+    /// Whether locking this resource locks the value at `index` of `segment`.
+    pub fn contains_index(&self, segment: &ResourceId, index: usize) -> bool {
+        match self.kind() {
+            ResourceKind::Named(_) => false,
+            ResourceKind::Segment(_) => self == segment,
+            ResourceKind::Chunk((start, end)) => {
+                (*start..*end).contains(&index) && self.parent() == Some(segment)
+            }
+            ResourceKind::Tuple(tuple_index) => {
+                *tuple_index == index && self.segment_of() == Some(segment)
+            }
+        }
+    }
+
+    /// The chunk this resource belongs to, itself for a chunk.
+    pub fn chunk_of(&self) -> Option<&ResourceId> {
+        match self.kind() {
+            ResourceKind::Chunk(_) => Some(self),
+            ResourceKind::Tuple(_) => self.parent(),
+            ResourceKind::Named(_) | ResourceKind::Segment(_) => None,
+        }
+    }
+}
+
+impl PartialEq for ResourceId {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+            || (self.0.hash == other.0.hash
+                && self.0.kind == other.0.kind
+                && self.0.parent == other.0.parent)
+    }
+}
+
+impl Eq for ResourceId {}
+
+/// Compares the displayed id, e.g. `seg/0,10` for a chunk.
+impl PartialEq<str> for ResourceId {
+    fn eq(&self, other: &str) -> bool {
+        let mut rest = DisplayPrefix(other);
+        write!(rest, "{}", self).is_ok() && rest.0.is_empty()
+    }
+}
+
+/// Consumes a string while it is written, failing at the first mismatch.
+struct DisplayPrefix<'a>(&'a str);
+
+impl std::fmt::Write for DisplayPrefix<'_> {
+    fn write_str(&mut self, s: &str) -> std::fmt::Result {
+        self.0 = self.0.strip_prefix(s).ok_or(std::fmt::Error)?;
+        Ok(())
+    }
+}
+
+impl PartialEq<&str> for ResourceId {
+    fn eq(&self, other: &&str) -> bool {
+        self == *other
+    }
+}
+
+impl PartialEq<ResourceId> for &str {
+    fn eq(&self, other: &ResourceId) -> bool {
+        other == *self
+    }
+}
+
+impl PartialEq<String> for ResourceId {
+    fn eq(&self, other: &String) -> bool {
+        self == other.as_str()
+    }
+}
+
+impl PartialEq<ResourceId> for String {
+    fn eq(&self, other: &ResourceId) -> bool {
+        other == self.as_str()
+    }
+}
+
+impl Ord for ResourceId {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        if Arc::ptr_eq(&self.0, &other.0) {
+            return std::cmp::Ordering::Equal;
+        }
+        (self.parent(), self.kind()).cmp(&(other.parent(), other.kind()))
+    }
+}
+
+impl PartialOrd for ResourceId {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Hash for ResourceId {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u64(self.0.hash);
+    }
+}
+
+impl Default for ResourceId {
+    fn default() -> Self {
+        Self::named("")
+    }
+}
+
+impl Display for ResourceId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match (self.kind(), self.parent()) {
+            (ResourceKind::Named(name) | ResourceKind::Segment(name), _) => write!(f, "{}", name),
+            (ResourceKind::Chunk((start, end)), Some(segment)) => {
+                write!(f, "{}/{},{}", segment, start, end)
+            }
+            (ResourceKind::Tuple(index), Some(chunk)) => match chunk.parent() {
+                Some(segment) => write!(f, "{}/{}", segment, index),
+                None => write!(f, "{}/{}", chunk, index),
+            },
+            (ResourceKind::Chunk((start, end)), None) => write!(f, "{},{}", start, end),
+            (ResourceKind::Tuple(index), None) => write!(f, "{}", index),
+        }
+    }
+}
+
+impl Debug for ResourceId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.to_string())
+    }
+}
+
+impl From<&str> for ResourceId {
+    fn from(name: &str) -> Self {
+        Self::named(name)
+    }
+}
+
+impl From<&ResourceId> for ResourceId {
+    fn from(rid: &ResourceId) -> Self {
+        rid.clone()
+    }
+}
+
+impl From<&String> for ResourceId {
+    fn from(name: &String) -> Self {
+        Self::named(name.as_str())
+    }
+}
+
+impl From<String> for ResourceId {
+    fn from(name: String) -> Self {
+        Self::named(name)
+    }
+}
+
+/// Structured id of a tuple: its segment and the set of indices it spans, each with the
+/// chunk holding it. Tuples of one segment overlap when they share an index, so they are
+/// locked index by index rather than under one id for the whole set.
 #[derive(Debug, Clone, Hash, Eq, PartialEq, Default)]
-pub struct Tuple {
-    pub tuple_id: ResourceId,
-    pub index: Vec<i32>,
-    pub values: Vec<i32>,
+pub struct TupleKey {
+    pub segment: ResourceId,
+    pub index: BTreeMap<usize, ResourceId>,
 }
 
-impl Tuple {
-    pub fn empty_tuple(idx_slice: &[i32]) -> Self {
-        Self {
-            tuple_id: idx_slice
-                .iter()
-                .map(|v| v.to_string())
-                .collect::<Vec<_>>()
-                .join(","),
-            index: Vec::from(idx_slice),
-            values: vec![],
+impl TupleKey {
+    /// The resources of all indices, in ascending index order.
+    pub fn index_resources(&self) -> Vec<ResourceId> {
+        self.index
+            .iter()
+            .map(|(index, chunk)| chunk.tuple(*index))
+            .collect()
+    }
+
+    /// The segment, then every chunk the indices fall into.
+    pub fn parents(&self) -> Vec<ResourceId> {
+        let mut parents = vec![self.segment.clone()];
+        for chunk in self.index.values() {
+            if !parents.contains(chunk) {
+                parents.push(chunk.clone());
+            }
         }
+        parents
     }
+
+    pub fn overlaps(&self, other: &TupleKey) -> bool {
+        self.segment == other.segment
+            && self
+                .index
+                .keys()
+                .any(|index| other.index.contains_key(index))
+    }
+}
+
+impl Display for TupleKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let index = self
+            .index
+            .keys()
+            .map(|index| index.to_string())
+            .collect::<Vec<_>>();
+        write!(f, "{}/({})", self.segment, index.join(","))
+    }
+}
+
+/// Key of the indices `[range.0, range.1)` of a segment, with every chunk they fall into.
+/// A range is locked through the hierarchy: each chunk inside it as a whole, the indices
+/// of the chunks it only partially covers one by one, below intention locks on the
+/// segment and those chunks. A write or another range inside it conflicts on a chunk or
+/// an index, so a scan holding the range sees no phantoms.
+///
+/// There is no interval check, a range costs one lock per whole chunk plus one per index
+/// in a partially covered chunk. `[100, 200)` in chunks of 10000 values takes 100 index
+/// locks, ranges aligned to chunk boundaries are the cheap ones.
+#[derive(Debug, Clone, Hash, Eq, PartialEq, Default)]
+pub struct RangeKey {
+    pub segment: ResourceId,
+    pub range: IndexRange,
+    pub chunks: Vec<ResourceId>,
+}
+
+impl RangeKey {
+    /// The resources locked in the requested mode: the chunks inside the range and each
+    /// index of the range in the other chunks, in ascending index order.
+    pub fn resources(&self) -> Vec<ResourceId> {
+        self.chunks
+            .iter()
+            .flat_map(|chunk| match self.partial(chunk) {
+                Some((start, end)) => (start..end).map(|index| chunk.tuple(index)).collect(),
+                None => vec![chunk.clone()],
+            })
+            .collect()
+    }
+
+    /// The segment, then every chunk the range only partially covers.
+    pub fn parents(&self) -> Vec<ResourceId> {
+        std::iter::once(self.segment.clone())
+            .chain(
+                self.chunks
+                    .iter()
+                    .filter(|chunk| self.partial(chunk).is_some())
+                    .cloned(),
+            )
+            .collect()
+    }
+
+    /// The indices of the range in `chunk`, `None` when the range covers all of it.
+    fn partial(&self, chunk: &ResourceId) -> Option<IndexRange> {
+        let ResourceKind::Chunk((start, end)) = chunk.kind() else {
+            unreachable!("a range key only holds chunks")
+        };
+        let covered = ((*start).max(self.range.0), (*end).min(self.range.1));
+        (covered != (*start, *end)).then_some(covered)
+    }
+}
+
+impl Display for RangeKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}[{},{})", self.segment, self.range.0, self.range.1)
+    }
+}
+
+#[derive(Debug, Clone, Hash, Eq, PartialEq, Default)]
+pub struct Tuple {
+    pub key: TupleKey,
+    pub index: Vec<i32>,
+    pub values: Vec<Value>,
 }
 
 #[derive(Debug, Clone, Hash, Eq, PartialEq)]
@@ -27,7 +396,7 @@ pub struct DataChunk {
     chunk_id: ResourceId,
     start: usize,
     end: usize,
-    seq_data: Vec<i32>,
+    seq_data: Vec<Value>,
 }
 
 #[derive(Debug, Clone, Hash, Eq, PartialEq)]
@@ -46,31 +415,32 @@ pub struct Segment {
 
 impl Segment {
     pub fn from_ints(capacity_per_chunk: usize, ints: &[i32], segment_id: String) -> Self {
-        let mut index = 0;
-        let mut chunks = vec![];
-        let mut is_break = false;
-        loop {
-            let start = index * capacity_per_chunk;
-            let end = if start + capacity_per_chunk >= ints.len() {
-                is_break = true;
-                ints.len() - 1
-            } else {
-                start + capacity_per_chunk
-            };
-            chunks.push(DataChunk {
-                chunk_id: [start.to_string(), end.to_string()].join(","),
-                start,
-                end,
-                seq_data: ints[start..end].to_owned(),
-            });
-            index += 1;
-            if is_break {
-                break;
-            }
-        }
+        let values = ints.iter().map(|int| Value::from(*int)).collect::<Vec<_>>();
+        Self::from_values(capacity_per_chunk, &values, segment_id)
+    }
+
+    /// A segment holding `values` in chunks of `capacity_per_chunk` values, the last chunk
+    /// takes the rest.
+    pub fn from_values(capacity_per_chunk: usize, values: &[Value], segment_id: String) -> Self {
+        assert!(capacity_per_chunk > 0, "chunks need a capacity");
+        let segment_id = ResourceId::segment(segment_id);
+        let chunks = values
+            .chunks(capacity_per_chunk)
+            .enumerate()
+            .map(|(pos, seq_data)| {
+                let start = pos * capacity_per_chunk;
+                let end = start + seq_data.len();
+                DataChunk {
+                    chunk_id: segment_id.chunk(start, end),
+                    start,
+                    end,
+                    seq_data: seq_data.to_owned(),
+                }
+            })
+            .collect();
         Self {
             segment_id,
-            capacity: ints.len(),
+            capacity: values.len(),
             capacity_per_chunk,
             chunks,
         }
@@ -84,52 +454,411 @@ impl Segment {
         self.capacity as i32
     }
 
-    pub fn get_chunk(&self, idx: usize) -> Option<&DataChunk> {
-        for chunk in self.chunks.iter() {
-            if chunk.end > idx {
-                return Some(chunk);
-            }
+    pub fn segment_id(&self) -> &ResourceId {
+        &self.segment_id
+    }
+
+    /// Resources above the tuple at `index` in the lock hierarchy: the segment, then every
+    /// chunk the indices fall into. Fails on the first index outside of the segment.
+    pub fn parent_resources(&self, index: &[i32]) -> Result<Vec<ResourceId>> {
+        Ok(self.tuple_key(index)?.parents())
+    }
+
+    /// The key of the tuple at `index`, fails on the first index outside of the segment.
+    pub fn tuple_key(&self, index: &[i32]) -> Result<TupleKey> {
+        let index = index
+            .iter()
+            .map(|idx| {
+                let idx = self.checked_index(*idx)?;
+                Ok((idx, self.get_chunk(idx)?.chunk_id.clone()))
+            })
+            .collect::<Result<_>>()?;
+        Ok(TupleKey {
+            segment: self.segment_id.clone(),
+            index,
+        })
+    }
+
+    /// The key of the indices `[range.0, range.1)`, fails when the range is empty or
+    /// reaches past the segment.
+    pub fn range_key(&self, range: IndexRange) -> Result<RangeKey> {
+        if range.0 >= range.1 {
+            return Err(anyhow!(SegmentError::EmptyRange(
+                range.0,
+                range.1,
+                self.segment_id.clone()
+            )));
+        }
+        let first = self.chunk_index(range.0)?.index;
+        let last = self.chunk_index(range.1 - 1)?.index;
+        Ok(RangeKey {
+            segment: self.segment_id.clone(),
+            range,
+            chunks: self.chunks[first..=last]
+                .iter()
+                .map(|chunk| chunk.chunk_id.clone())
+                .collect(),
+        })
+    }
+
+    /// The tuple at `index` without its values, enough to lock it. Fails on the first index
+    /// outside of the segment.
+    pub fn empty_tuple(&self, index: &[i32]) -> Result<Tuple> {
+        Ok(Tuple {
+            key: self.tuple_key(index)?,
+            index: Vec::from(index),
+            values: vec![],
+        })
+    }
+
+    /// The chunk holding `idx`, every chunk but the last one holds `capacity_per_chunk`
+    /// values.
+    pub fn get_chunk(&self, idx: usize) -> Result<&DataChunk> {
+        let chunk_index = self.chunk_index(idx)?;
+        Ok(&self.chunks[chunk_index.index])
+    }
+
+    /// A copy of the value at `index`.
+    pub fn get_value(&self, index: usize) -> Result<Value> {
+        let chunk_index = self.chunk_index(index)?;
+        Ok(self.chunks[chunk_index.index].seq_data[chunk_index.value_index].clone())
+    }
+
+    pub fn update_value(&mut self, index: usize, new_value: impl Into<Value>) -> Result<()> {
+        self.replace_value(index, new_value).map(|_| ())
+    }
+
+    /// Store `new_value` at `index` and hand back the value it replaces, without a copy.
+    pub fn replace_value(&mut self, index: usize, new_value: impl Into<Value>) -> Result<Value> {
+        let chunk_index = self.chunk_index(index)?;
+        Ok(std::mem::replace(
+            &mut self.chunks[chunk_index.index].seq_data[chunk_index.value_index],
+            new_value.into(),
+        ))
+    }
+
+    fn chunk_index(&self, index: usize) -> Result<DataChunkIndex> {
+        if index >= self.capacity {
+            return Err(anyhow!(SegmentError::IndexOutOfRange(
+                index as i64,
+                self.segment_id.clone()
+            )));
         }
-        self.chunks.last()
+        Ok(DataChunkIndex {
+            index: index / self.capacity_per_chunk,
+            value_index: index % self.capacity_per_chunk,
+        })
     }
 
-    pub fn update_value(&mut self, index: usize, new_value: i32) {
-        let chunk_index_vec = self.get_chunk_index(&[index as i32]);
-        assert_eq!(chunk_index_vec.len(), 1);
-        let chunk_index = &chunk_index_vec[0];
-        self.chunks[chunk_index.index].seq_data[chunk_index.value_index] = new_value;
+    /// `idx` as an index of a value, fails when it is outside of the segment.
+    fn checked_index(&self, idx: i32) -> Result<usize> {
+        match usize::try_from(idx) {
+            Ok(index) if index < self.capacity => Ok(index),
+            _ => Err(anyhow!(SegmentError::IndexOutOfRange(
+                idx as i64,
+                self.segment_id.clone()
+            ))),
+        }
     }
 
-    fn get_chunk_index(&self, index: &[i32]) -> Vec<DataChunkIndex> {
-        let mut data = vec![];
-        index.iter().for_each(|idx| {
-            let chunk_opt = self.get_chunk(*idx as usize);
-            if let Some(chunk) = chunk_opt {
-                data.push(DataChunkIndex {
-                    index: *idx as usize,
-                    value_index: *idx as usize - chunk.start as usize,
-                });
+    /// The tuple at `index` with its values, fails on the first index outside of the
+    /// segment.
+    pub fn get_tuple(&self, index: &[i32]) -> Result<Tuple> {
+        let key = self.tuple_key(index)?;
+        let values = index
+            .iter()
+            .map(|idx| self.get_value(*idx as usize))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Tuple {
+            key,
+            index: Vec::from(index),
+            values,
+        })
+    }
+
+    /// Write the segment to `path` in the snapshot format, through a temporary file so a
+    /// crash never leaves half a snapshot behind. The file is synced before it is renamed
+    /// and the directory after, so the snapshot is on disk once `save` returns.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let tmp_path = path.with_extension("tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&self.to_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(tmp_path, path)?;
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        File::open(dir)?.sync_all()?;
+        Ok(())
+    }
+
+    /// Read a segment written by `save`.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        Self::from_bytes(&std::fs::read(path)?)
+    }
+
+    /// The snapshot format, all integers little endian:
+    /// `magic, version, segment id, capacity, capacity_per_chunk, chunk count`, then for
+    /// every chunk `start, end` and its `end - start` values, each a kind byte and its
+    /// payload, closed by the CRC-32 of all bytes before it.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut encoder = Encoder::new();
+        encoder
+            .u32(SNAPSHOT_MAGIC)
+            .u32(SNAPSHOT_VERSION)
+            .str(&self.segment_id.to_string())
+            .u64(self.capacity as u64)
+            .u64(self.capacity_per_chunk as u64)
+            .u32(self.chunks.len() as u32);
+        for chunk in self.chunks.iter() {
+            encoder.u64(chunk.start as u64).u64(chunk.end as u64);
+            for value in chunk.seq_data.iter() {
+                value.encode(&mut encoder);
             }
-        });
-        data
+        }
+        let mut bytes = encoder.finish();
+        bytes.extend_from_slice(&crc32(&bytes).to_le_bytes());
+        bytes
     }
 
-    pub fn get_tuple(&self, index: &[i32]) -> Tuple {
-        let mut seq_vals = vec![];
-        index.iter().for_each(|idx| {
-            let chunk_opt = self.get_chunk(*idx as usize);
-            if let Some(chunk) = chunk_opt {
-                seq_vals.push(chunk.seq_data[*idx as usize - chunk.start as usize])
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let Some((body, crc)) = bytes.split_last_chunk::<4>() else {
+            return Err(anyhow!(SnapshotErrorCode::Truncated(bytes.len())));
+        };
+        let (expected, actual) = (u32::from_le_bytes(*crc), crc32(body));
+        if expected != actual {
+            return Err(anyhow!(SnapshotErrorCode::ChecksumMismatch(
+                expected, actual
+            )));
+        }
+        let mut decoder = Decoder::new(body);
+        let magic = decoder.u32()?;
+        if magic != SNAPSHOT_MAGIC {
+            return Err(anyhow!(SnapshotErrorCode::BadMagic(magic)));
+        }
+        let version = decoder.u32()?;
+        if version != SNAPSHOT_VERSION {
+            return Err(anyhow!(SnapshotErrorCode::UnsupportedVersion(version)));
+        }
+        let segment_id = ResourceId::segment(decoder.str()?);
+        let capacity = decoder.u64()? as usize;
+        let capacity_per_chunk = decoder.u64()? as usize;
+        let chunk_count = decoder.u32()?;
+        if capacity_per_chunk == 0 || chunk_count as usize != capacity.div_ceil(capacity_per_chunk)
+        {
+            return Err(anyhow!(SnapshotErrorCode::ChunkCount(
+                capacity,
+                capacity_per_chunk,
+                chunk_count as usize
+            )));
+        }
+        let mut chunks = vec![];
+        for pos in 0..chunk_count as usize {
+            let start = decoder.u64()? as usize;
+            let end = decoder.u64()? as usize;
+            // Chunks are addressed by arithmetic, they must lie where it expects them.
+            let expected_start = pos * capacity_per_chunk;
+            let expected_end = capacity.min(expected_start + capacity_per_chunk);
+            if (start, end) != (expected_start, expected_end) {
+                return Err(anyhow!(SnapshotErrorCode::ChunkLayout(
+                    pos,
+                    expected_start,
+                    expected_end
+                )));
             }
-        });
-        Tuple {
-            tuple_id: index
-                .iter()
-                .map(|x| x.to_string())
-                .collect::<Vec<_>>()
-                .join(","),
-            index: Vec::from(index),
-            values: seq_vals,
+            let seq_data = (start..end)
+                .map(|_| Value::decode(&mut decoder))
+                .collect::<Result<Vec<_>>>()?;
+            chunks.push(DataChunk {
+                chunk_id: segment_id.chunk(start, end),
+                start,
+                end,
+                seq_data,
+            });
         }
+        if !decoder.is_empty() {
+            return Err(anyhow!(SnapshotErrorCode::TrailingBytes));
+        }
+        Ok(Self {
+            segment_id,
+            capacity,
+            capacity_per_chunk,
+            chunks,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::segment::{ResourceId, ResourceKind, Segment, SegmentError, SnapshotErrorCode};
+    use crate::value::Value;
+    use std::collections::HashSet;
+
+    #[test]
+    pub fn test_resource_hierarchy() {
+        let ints = (0..30).collect::<Vec<_>>();
+        let segment = Segment::from_ints(10, &ints, "rid_seg".to_string());
+        let tuple = segment
+            .tuple_key(&[15])
+            .unwrap()
+            .index_resources()
+            .remove(0);
+        let chunk = tuple.parent().unwrap().clone();
+        assert_eq!(&ResourceKind::Tuple(15), tuple.kind());
+        assert_eq!(&ResourceKind::Chunk((10, 20)), chunk.kind());
+        assert_eq!(
+            vec![segment.segment_id().clone(), chunk.clone()],
+            tuple.ancestors()
+        );
+        assert_eq!(Some(segment.segment_id()), tuple.segment_of());
+        assert_eq!(Some(&chunk), tuple.chunk_of());
+        assert_eq!("rid_seg/15", tuple);
+        assert_eq!("rid_seg/10,20", chunk);
+
+        // Built separately, equal ids hash the same.
+        let rebuilt = ResourceId::segment("rid_seg").chunk(10, 20).tuple(15);
+        assert_eq!(tuple, rebuilt);
+        assert_eq!(1, HashSet::from([tuple.clone(), rebuilt]).len());
+        // A named id is never part of a segment, even when displayed the same.
+        assert_ne!(tuple, ResourceId::named("rid_seg/15"));
+        assert!(ResourceId::named("rid_seg/15").ancestors().is_empty());
+    }
+
+    #[test]
+    pub fn test_snapshot() {
+        let ints = (0..1000).map(|int| int * 7 - 500).collect::<Vec<_>>();
+        let mut segment = Segment::from_ints(64, &ints, "snap_seg".to_string());
+        segment.update_value(130, i32::MIN).unwrap();
+        segment.update_value(131, "payload").unwrap();
+        segment.update_value(132, vec![7_u8; 512]).unwrap();
+        segment.update_value(133, -1.5).unwrap();
+        let path = std::env::temp_dir().join(format!("r_tpl_snap_{}.seg", std::process::id()));
+        segment.save(&path).unwrap();
+        let loaded = Segment::load(&path).unwrap();
+        assert_eq!(segment, loaded);
+        assert_eq!(i32::MIN, loaded.get_value(130).unwrap());
+        assert_eq!(Value::from("payload"), loaded.get_value(131).unwrap());
+        assert_eq!(512, loaded.get_value(132).unwrap().size());
+        assert_eq!(
+            "snap_seg/128,192",
+            loaded.tuple_key(&[130]).unwrap().parents()[1]
+        );
+        std::fs::remove_file(path).unwrap();
+
+        let mut bytes = segment.to_bytes();
+        bytes[100] ^= 1;
+        assert!(matches!(
+            Segment::from_bytes(&bytes)
+                .unwrap_err()
+                .downcast_ref::<SnapshotErrorCode>(),
+            Some(SnapshotErrorCode::ChecksumMismatch(_, _))
+        ));
+        assert!(matches!(
+            Segment::from_bytes(&bytes[..3])
+                .unwrap_err()
+                .downcast_ref::<SnapshotErrorCode>(),
+            Some(SnapshotErrorCode::Truncated(3))
+        ));
+    }
+
+    #[test]
+    pub fn test_chunk_addressing() {
+        let ints = (0..25).collect::<Vec<_>>();
+        let mut segment = Segment::from_ints(10, &ints, "addr_seg".to_string());
+        let bounds = segment
+            .chunks()
+            .iter()
+            .map(|chunk| (chunk.start, chunk.end))
+            .collect::<Vec<_>>();
+        assert_eq!(vec![(0, 10), (10, 20), (20, 25)], bounds);
+        // Every value is kept, the last one included.
+        assert_eq!(
+            ints,
+            (0..25)
+                .map(|idx| segment.get_value(idx).unwrap())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(20, segment.get_chunk(24).unwrap().start);
+        assert!(matches!(
+            segment
+                .get_chunk(25)
+                .unwrap_err()
+                .downcast_ref::<SegmentError>(),
+            Some(SegmentError::IndexOutOfRange(25, _))
+        ));
+
+        segment.update_value(24, -24).unwrap();
+        assert_eq!(
+            vec![9, 10, -24],
+            segment.get_tuple(&[9, 10, 24]).unwrap().values
+        );
+        assert!(segment.update_value(25, 0).is_err());
+        assert!(matches!(
+            segment
+                .get_tuple(&[3, -1])
+                .unwrap_err()
+                .downcast_ref::<SegmentError>(),
+            Some(SegmentError::IndexOutOfRange(-1, _))
+        ));
+        // Keys fail the same way instead of leaving the bad indices out.
+        assert!(matches!(
+            segment
+                .tuple_key(&[3, 25])
+                .unwrap_err()
+                .downcast_ref::<SegmentError>(),
+            Some(SegmentError::IndexOutOfRange(25, _))
+        ));
+        assert!(matches!(
+            segment
+                .empty_tuple(&[-1])
+                .unwrap_err()
+                .downcast_ref::<SegmentError>(),
+            Some(SegmentError::IndexOutOfRange(-1, _))
+        ));
+        assert!(segment.parent_resources(&[30]).is_err());
+
+        let key = segment.range_key((8, 22)).unwrap();
+        assert_eq!(
+            vec!["addr_seg", "addr_seg/0,10", "addr_seg/20,25"],
+            key.parents()
+        );
+        assert_eq!(
+            vec![
+                "addr_seg/8",
+                "addr_seg/9",
+                "addr_seg/10,20",
+                "addr_seg/20",
+                "addr_seg/21"
+            ],
+            key.resources()
+        );
+        assert_eq!(
+            vec!["addr_seg"],
+            segment.range_key((0, 25)).unwrap().parents()
+        );
+        assert!(matches!(
+            segment
+                .range_key((3, 3))
+                .unwrap_err()
+                .downcast_ref::<SegmentError>(),
+            Some(SegmentError::EmptyRange(3, 3, _))
+        ));
+        assert!(matches!(
+            segment
+                .range_key((20, 26))
+                .unwrap_err()
+                .downcast_ref::<SegmentError>(),
+            Some(SegmentError::IndexOutOfRange(25, _))
+        ));
+
+        // A chunk size dividing the values evenly leaves no empty chunk behind.
+        let even = Segment::from_ints(5, &ints, "addr_even".to_string());
+        assert_eq!(5, even.chunks().len());
+        assert_eq!(24, even.get_value(24).unwrap());
+        assert!(Segment::from_ints(5, &[], "addr_empty".to_string())
+            .get_value(0)
+            .is_err());
     }
 }
@@ -0,0 +1,460 @@
+use crate::operation::OpType;
+use crate::segment::{ResourceId, Segment, TupleKey};
+use crate::transaction::Transaction;
+use crate::value::Value;
+use crate::wal::Wal;
+use anyhow::Result;
+use parking_lot::{RwLock, RwLockReadGuard};
+use std::sync::Arc;
+
+/// Transactional access to the data of one segment. `read` and `write` first lock the
+/// tuple at `index` through the transaction, shared or exclusive, and only then touch the
+/// segment, so the values a transaction sees and changes are isolated by its 2PL locks.
+///
+/// The segment itself sits behind a latch that is held for the copy in or out of one
+/// value only, never while waiting for a lock.
+#[derive(Debug)]
+pub struct SegmentStore {
+    segment_id: ResourceId,
+    segment: Arc<RwLock<Segment>>,
+    wal: Option<Arc<Wal>>,
+}
+
+impl SegmentStore {
+    pub fn new(segment: Segment) -> Self {
+        Self {
+            segment_id: segment.segment_id().clone(),
+            segment: Arc::new(RwLock::new(segment)),
+            wal: None,
+        }
+    }
+
+    /// Log every transactional update in `wal` before applying it.
+    pub fn with_wal(mut self, wal: Arc<Wal>) -> Self {
+        self.wal = Some(wal);
+        self
+    }
+
+    pub fn segment_id(&self) -> &ResourceId {
+        &self.segment_id
+    }
+
+    /// The segment as it is now, with the changes of transactions still running. Must not
+    /// be held while acquiring locks, aborts restore values with the lock table latched.
+    pub fn segment(&self) -> RwLockReadGuard<'_, Segment> {
+        self.segment.read()
+    }
+
+    /// Read the value at `index` under a shared lock of `txn`.
+    pub fn read(&self, txn: &mut Transaction, index: usize) -> Result<Value> {
+        txn.acquire_tuple(self.tuple_key(index)?, OpType::Read)?;
+        self.segment.read().get_value(index)
+    }
+
+    /// Async flavour of `read`, waiting for the lock without parking the thread.
+    pub async fn read_async(&self, txn: &mut Transaction, index: usize) -> Result<Value> {
+        txn.acquire_tuple_async(self.tuple_key(index)?, OpType::Read)
+            .await?;
+        self.segment.read().get_value(index)
+    }
+
+    /// Overwrite the value at `index` under an exclusive lock of `txn`, undone when `txn`
+    /// aborts.
+    pub fn write(
+        &self,
+        txn: &mut Transaction,
+        index: usize,
+        value: impl Into<Value>,
+    ) -> Result<()> {
+        txn.acquire_tuple(self.tuple_key(index)?, OpType::Write)?;
+        self.write_locked(txn, index, value.into())
+    }
+
+    /// Async flavour of `write`, waiting for the lock without parking the thread.
+    pub async fn write_async(
+        &self,
+        txn: &mut Transaction,
+        index: usize,
+        value: impl Into<Value>,
+    ) -> Result<()> {
+        txn.acquire_tuple_async(self.tuple_key(index)?, OpType::Write)
+            .await?;
+        self.write_locked(txn, index, value.into())
+    }
+
+    fn write_locked(&self, txn: &mut Transaction, index: usize, value: Value) -> Result<()> {
+        txn.write_logged(
+            &self.segment_id,
+            &self.segment,
+            self.wal.as_ref(),
+            index,
+            value,
+        )
+    }
+
+    fn tuple_key(&self, index: usize) -> Result<TupleKey> {
+        let segment = self.segment.read();
+        segment.get_value(index)?;
+        segment.tuple_key(&[index as i32])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lock_mgr::{DeadLockPrevention, LockErrorCode, LockManager, LockTable};
+    use crate::operation::OpType::*;
+    use crate::operation::Operation;
+    use crate::segment::{Segment, SegmentError};
+    use crate::segment_store::SegmentStore;
+    use crate::transaction::{Transaction, TransactionErrorCode, TransactionState};
+    use crate::undo_log::UndoState;
+    use crate::value::Value;
+    use crate::wal::Wal;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn store(name: &str) -> SegmentStore {
+        let ints = (0..30).collect::<Vec<_>>();
+        SegmentStore::new(Segment::from_ints(10, &ints, name.to_string()))
+    }
+
+    #[test]
+    pub fn test_read_write() {
+        let lock_table = Arc::new(LockTable::new());
+        let store = store("store_rw");
+        let mut txn = Transaction::new(lock_table.clone(), "store_rw_1".to_string());
+        assert_eq!(12, store.read(&mut txn, 12).unwrap());
+        store.write(&mut txn, 12, 120).unwrap();
+        store.write(&mut txn, 25, 250).unwrap();
+        // Its own writes are visible to the transaction.
+        assert_eq!(120, store.read(&mut txn, 12).unwrap());
+        assert!(matches!(
+            store
+                .read(&mut txn, 100)
+                .unwrap_err()
+                .downcast_ref::<SegmentError>(),
+            Some(SegmentError::IndexOutOfRange(100, _))
+        ));
+        // Only a rolled back log aborts the writer, a bad index leaves it running.
+        assert!(matches!(
+            txn.write_logged(
+                store.segment_id(),
+                &store.segment,
+                None,
+                100,
+                Value::from(1)
+            )
+            .unwrap_err()
+            .downcast_ref::<SegmentError>(),
+            Some(SegmentError::IndexOutOfRange(100, _))
+        ));
+        assert_eq!(TransactionState::Growing, txn.state());
+        txn.commit().unwrap();
+
+        let mut txn = Transaction::new(lock_table, "store_rw_2".to_string());
+        assert_eq!(120, store.read(&mut txn, 12).unwrap());
+        assert_eq!(250, store.read(&mut txn, 25).unwrap());
+        assert_eq!(13, store.read(&mut txn, 13).unwrap());
+        txn.commit().unwrap();
+        assert_eq!(120, store.segment().get_value(12).unwrap());
+    }
+
+    #[test]
+    pub fn test_write_isolated_by_locks() {
+        let lock_table = Arc::new(LockTable::new());
+        let store = Arc::new(store("store_iso"));
+        let mut writer = Transaction::new(lock_table.clone(), "store_iso_w".to_string());
+        store.write(&mut writer, 5, 50).unwrap();
+
+        let reader = {
+            let store = store.clone();
+            let lock_table = lock_table.clone();
+            std::thread::spawn(move || {
+                let mut reader = Transaction::new(lock_table, "store_iso_r".to_string());
+                let value = store.read(&mut reader, 5);
+                reader.commit().unwrap();
+                value
+            })
+        };
+        std::thread::sleep(Duration::from_millis(20));
+        // The reader waits for the uncommitted write.
+        assert!(!reader.is_finished());
+        // Other tuples of the chunk stay available.
+        let mut other = Transaction::new(lock_table, "store_iso_o".to_string());
+        store.write(&mut other, 6, 60).unwrap();
+        other.commit().unwrap();
+
+        store.write(&mut writer, 5, 55).unwrap();
+        writer.commit().unwrap();
+        assert_eq!(55, reader.join().unwrap().unwrap());
+    }
+
+    #[test]
+    pub fn test_write_conflict_aborts() {
+        let lock_table = Arc::new(LockTable::new());
+        let store = store("store_die");
+        let mut older = Transaction::new(lock_table.clone(), "store_die_old".to_string())
+            .with_prevention(DeadLockPrevention::WaitDie);
+        let mut younger = Transaction::new(lock_table, "store_die_young".to_string())
+            .with_prevention(DeadLockPrevention::WaitDie);
+        store.read(&mut older, 3).unwrap();
+        assert!(matches!(
+            store
+                .write(&mut younger, 3, 30)
+                .unwrap_err()
+                .downcast_ref::<LockErrorCode>(),
+            Some(LockErrorCode::WaitDieAbort(_, _))
+        ));
+        assert_eq!(3, store.segment().get_value(3).unwrap());
+        older.commit().unwrap();
+    }
+
+    #[tokio::test]
+    pub async fn test_async_read_write() {
+        let lock_table = Arc::new(LockTable::new());
+        let store = Arc::new(store("store_async"));
+        let mut writer = Transaction::new(lock_table.clone(), "store_async_w".to_string());
+        store.write_async(&mut writer, 9, 90).await.unwrap();
+        assert!(matches!(
+            store
+                .read_async(&mut writer, 30)
+                .await
+                .unwrap_err()
+                .downcast_ref::<SegmentError>(),
+            Some(SegmentError::IndexOutOfRange(30, _))
+        ));
+
+        let reader = {
+            let store = store.clone();
+            let lock_table = lock_table.clone();
+            tokio::spawn(async move {
+                let mut reader = Transaction::new(lock_table, "store_async_r".to_string());
+                let value = store.read_async(&mut reader, 9).await;
+                reader.commit().unwrap();
+                value
+            })
+        };
+        // The reader waits for the uncommitted write.
+        while !lock_table
+            .dead_lock_detector()
+            .has_outgoing("store_async_r")
+        {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        writer.commit().unwrap();
+        assert_eq!(90, reader.await.unwrap().unwrap());
+    }
+
+    fn values(store: &SegmentStore) -> Vec<i64> {
+        let segment = store.segment();
+        (0..30)
+            .map(|idx| segment.get_value(idx).unwrap().as_int().unwrap())
+            .collect()
+    }
+
+    #[test]
+    pub fn test_abort_restores_before_images() {
+        let lock_table = Arc::new(LockTable::new());
+        let store = store("undo_abort");
+        let before = values(&store);
+        let mut txn = Transaction::new(lock_table.clone(), "undo_abort_1".to_string());
+        store.write(&mut txn, 4, 40).unwrap();
+        store.write(&mut txn, 4, 41).unwrap();
+        store.write(&mut txn, 17, 170).unwrap();
+        assert_eq!(3, txn.undo_log().records().len());
+        assert_eq!(4, txn.undo_log().records()[0].before);
+        assert_eq!(40, txn.undo_log().records()[1].before);
+        txn.abort().unwrap();
+        assert_eq!(before, values(&store));
+        assert_eq!(UndoState::RolledBack, txn.undo_log().state());
+
+        // Committed writes stay.
+        let mut txn = Transaction::new(lock_table, "undo_abort_2".to_string());
+        store.write(&mut txn, 4, 44).unwrap();
+        txn.commit().unwrap();
+        assert!(txn.undo_log().records().is_empty());
+        assert_eq!(44, store.segment().get_value(4).unwrap());
+    }
+
+    #[test]
+    pub fn test_written_lock_held_until_abort() {
+        let lock_table = Arc::new(LockTable::new());
+        let store = store("undo_held");
+        let mut txn = Transaction::new(lock_table.clone(), "undo_held_a".to_string());
+        store.write(&mut txn, 5, 50).unwrap();
+        assert_eq!(6, store.read(&mut txn, 6).unwrap());
+        let (written, read) = (txn.locks()[0].clone(), txn.locks()[1].clone());
+        // Basic 2PL lets the read go, but not the write an abort would undo.
+        assert!(matches!(
+            txn.release(&written)
+                .unwrap_err()
+                .downcast_ref::<TransactionErrorCode>(),
+            Some(TransactionErrorCode::WrittenRelease(_, _))
+        ));
+        txn.release(&read).unwrap();
+        let writer = LockManager::new(
+            lock_table.clone(),
+            Operation::for_tuple(
+                "undo_held_b".to_string(),
+                store.segment().tuple_key(&[5]).unwrap(),
+                Write,
+            ),
+        );
+        assert!(writer.try_acquire(Duration::ZERO).is_err());
+        txn.abort().unwrap();
+
+        let mut other = Transaction::new(lock_table, "undo_held_c".to_string());
+        store.write(&mut other, 5, 99).unwrap();
+        other.commit().unwrap();
+        assert_eq!(99, store.segment().get_value(5).unwrap());
+    }
+
+    #[test]
+    pub fn test_concurrent_aborts() {
+        let lock_table = Arc::new(LockTable::new());
+        let store = Arc::new(store("undo_conc"));
+        let before = values(&store);
+        let workers = (0..8)
+            .map(|worker| {
+                let lock_table = lock_table.clone();
+                let store = store.clone();
+                std::thread::spawn(move || {
+                    let mut committed = 0;
+                    for round in 0..20 {
+                        let mut txn = Transaction::new(
+                            lock_table.clone(),
+                            format!("undo_conc_{}_{}", worker, round),
+                        );
+                        // Index 0 counts the commits, each worker also owns 3 indices.
+                        let counter = store.segment().tuple_key(&[0]).unwrap();
+                        txn.acquire_tuple(counter, Write).unwrap();
+                        let count = store.read(&mut txn, 0).unwrap();
+                        store
+                            .write(&mut txn, 0, count.as_int().unwrap() + 1)
+                            .unwrap();
+                        for idx in worker * 3 + 1..worker * 3 + 4 {
+                            store.write(&mut txn, idx, -(round as i32)).unwrap();
+                        }
+                        if (worker + round) % 2 == 0 {
+                            txn.commit().unwrap();
+                            committed += 1;
+                        } else {
+                            txn.abort().unwrap();
+                        }
+                    }
+                    committed
+                })
+            })
+            .collect::<Vec<_>>();
+        let committed = workers
+            .into_iter()
+            .map(|worker| worker.join().unwrap())
+            .sum::<i64>();
+
+        let after = values(&store);
+        assert_eq!(before[0] + committed, after[0]);
+        for worker in 0..8 {
+            // Even workers commit on even rounds, the last one is 18, odd ones on 19.
+            let last_commit = if worker % 2 == 0 { -18 } else { -19 };
+            assert_eq!([last_commit; 3], after[worker * 3 + 1..worker * 3 + 4]);
+        }
+        assert_eq!(before[25..], after[25..]);
+    }
+
+    #[test]
+    pub fn test_dead_lock_victim_rolled_back() {
+        let lock_table = Arc::new(LockTable::new());
+        let store = Arc::new(store("undo_victim"));
+        let mut older = Transaction::new(lock_table.clone(), "undo_victim_old".to_string());
+        let mut younger = Transaction::new(lock_table.clone(), "undo_victim_young".to_string());
+        store.write(&mut older, 1, 100).unwrap();
+        store.write(&mut younger, 2, 200).unwrap();
+
+        let older_join = {
+            let store = store.clone();
+            std::thread::spawn(move || store.write(&mut older, 2, 101).and(older.commit()))
+        };
+        let younger_join = {
+            let store = store.clone();
+            std::thread::spawn(move || (store.write(&mut younger, 1, 201), younger))
+        };
+        while lock_table.detect_dead_locks().is_empty() {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(vec!["undo_victim_young"], lock_table.resolve_dead_locks());
+
+        let (write_rs, younger) = younger_join.join().unwrap();
+        assert!(matches!(
+            write_rs.unwrap_err().downcast_ref::<LockErrorCode>(),
+            Some(LockErrorCode::Deadlock(_, _))
+        ));
+        assert_eq!(TransactionState::Aborted, younger.state());
+        older_join.join().unwrap().unwrap();
+        // The victim's write of 2 was undone before the older transaction got its lock.
+        assert_eq!(100, store.segment().get_value(1).unwrap());
+        assert_eq!(101, store.segment().get_value(2).unwrap());
+    }
+
+    #[test]
+    pub fn test_wounded_writer_rolled_back() {
+        let lock_table = Arc::new(LockTable::new());
+        let store = store("undo_wound");
+        let mut older = Transaction::new(lock_table.clone(), "undo_wound_old".to_string())
+            .with_prevention(DeadLockPrevention::WoundWait);
+        let mut younger = Transaction::new(lock_table, "undo_wound_young".to_string())
+            .with_prevention(DeadLockPrevention::WoundWait);
+        store.write(&mut younger, 7, 70).unwrap();
+        store.write(&mut younger, 8, 80).unwrap();
+        // Wounded while it is not waiting, its locks and writes are gone at once.
+        store.write(&mut older, 7, 77).unwrap();
+        assert_eq!(8, store.segment().get_value(8).unwrap());
+        assert_eq!(UndoState::RolledBack, younger.undo_log().state());
+        assert!(matches!(
+            younger
+                .commit()
+                .unwrap_err()
+                .downcast_ref::<TransactionErrorCode>(),
+            Some(TransactionErrorCode::NotActive(
+                _,
+                TransactionState::Aborted
+            ))
+        ));
+        older.commit().unwrap();
+        assert_eq!(77, store.segment().get_value(7).unwrap());
+    }
+
+    #[test]
+    pub fn test_payload_values() {
+        let lock_table = Arc::new(LockTable::new());
+        let path = std::env::temp_dir().join(format!("r_tpl_payload_{}.wal", std::process::id()));
+        let records = (0..20)
+            .map(|idx| Value::from(format!("record {}", idx)))
+            .collect::<Vec<_>>();
+        let snapshot = Segment::from_values(8, &records, "store_payload".to_string());
+        let wal = Arc::new(Wal::create(&path).unwrap());
+        let store = SegmentStore::new(snapshot.clone()).with_wal(wal.clone());
+
+        let mut txn = Transaction::new(lock_table.clone(), "payload_1".to_string());
+        store.write(&mut txn, 3, vec![0_u8; 4096]).unwrap();
+        store.write(&mut txn, 19, 2.5).unwrap();
+        assert_eq!(4096, store.read(&mut txn, 3).unwrap().size());
+        txn.abort().unwrap();
+        assert_eq!(
+            records,
+            (0..20)
+                .map(|idx| store.segment().get_value(idx).unwrap())
+                .collect::<Vec<_>>()
+        );
+
+        let mut txn = Transaction::new(lock_table, "payload_2".to_string());
+        store.write(&mut txn, 3, "changed").unwrap();
+        txn.commit().unwrap();
+        let recovery = wal.recover(snapshot).unwrap();
+        assert_eq!(
+            Value::from("changed"),
+            recovery.segment.get_value(3).unwrap()
+        );
+        assert_eq!(records[19], recovery.segment.get_value(19).unwrap());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,1064 @@
+use crate::lock::{Lock, LockMode, OP_LOCK_MAPPING};
+use crate::lock_mgr::{DeadLockPrevention, LockErrorCode, LockManager, LockTable};
+use crate::operation::{timestamp_nanos, OpType, Operation};
+use crate::segment::{RangeKey, ResourceId, Segment, TupleKey};
+use crate::undo_log::UndoLog;
+use crate::value::Value;
+use crate::wal::Wal;
+use anyhow::{anyhow, Result};
+use parking_lot::RwLock;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum TransactionState {
+    /// Locks may be acquired (and released, which ends the phase).
+    Growing,
+    /// At least one lock was released, acquiring is a 2PL violation.
+    Shrinking,
+    Committed,
+    Aborted,
+}
+
+/// When a transaction may give its locks back.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Hash)]
+pub enum TwoPhaseLocking {
+    /// Any lock may be released once the growing phase is over.
+    #[default]
+    Basic,
+    /// Exclusive locks are held until commit/abort, shared ones may go early.
+    Strict,
+    /// Every lock is held until commit/abort.
+    Rigorous,
+}
+
+impl TwoPhaseLocking {
+    pub fn allow_release(&self, lock_mode: LockMode) -> bool {
+        match self {
+            TwoPhaseLocking::Basic => true,
+            TwoPhaseLocking::Strict => lock_mode.intention() != LockMode::IntentionExclusive,
+            TwoPhaseLocking::Rigorous => false,
+        }
+    }
+}
+
+/// Thresholds above which a transaction trades its fine-grained locks below a resource
+/// for a single lock on that resource.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct LockEscalation {
+    /// Tuples locked inside one chunk before the whole chunk is locked instead.
+    pub tuples_per_chunk: usize,
+    /// Chunks locked inside one segment before the whole segment is locked instead.
+    pub chunks_per_segment: usize,
+}
+
+impl Default for LockEscalation {
+    fn default() -> Self {
+        Self {
+            tuples_per_chunk: 64,
+            chunks_per_segment: 8,
+        }
+    }
+}
+
+/// The transaction `txn_id` replaced `released` locks below `rid` by one `lock_mode` lock.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct EscalationEvent {
+    pub txn_id: String,
+    pub rid: ResourceId,
+    pub lock_mode: LockMode,
+    pub released: usize,
+}
+
+#[derive(Error, Debug, Clone, Eq, PartialEq)]
+pub enum TransactionErrorCode {
+    #[error("2PL violation TXN_ID {0} acquires RES_ID {1} after releasing a lock")]
+    AcquireInShrinking(String, ResourceId),
+    #[error("Transaction TXN_ID {0} is already {1:?}")]
+    NotActive(String, TransactionState),
+    #[error("{2:?} 2PL holds RES_ID {1} of TXN_ID {0} until commit or abort")]
+    EarlyRelease(String, ResourceId, TwoPhaseLocking),
+    #[error("TXN_ID {0} wrote below RES_ID {1}, its lock is held until commit or abort")]
+    WrittenRelease(String, ResourceId),
+}
+
+/// A transaction owning locks on many resources over its lifetime. Every lock is taken
+/// through a `LockManager` whose operation carries the transaction id and timestamp, so
+/// the lock table sees all of them as held by the same operation.
+///
+/// The growing phase ends with the first `release`, after which `acquire` fails with
+/// `AcquireInShrinking`. Under strict or rigorous 2PL `release` rejects the locks that
+/// must be kept, under any protocol it rejects the locks on values the transaction wrote,
+/// an abort could otherwise undo what others wrote after them. `commit` and `abort`
+/// release whatever is still held in one step.
+///
+/// Locks taken with segment and chunk parents are escalated following `LockEscalation`.
+/// The escalated lock is only taken when it is granted without waiting, otherwise the
+/// fine-grained locks are kept and escalation is tried again with the next lock.
+#[derive(Debug, Clone)]
+pub struct Transaction {
+    lock_table: Arc<LockTable>,
+    txn_id: String,
+    timestamp: u128,
+    state: TransactionState,
+    prevention: DeadLockPrevention,
+    protocol: TwoPhaseLocking,
+    escalation: LockEscalation,
+    held: Vec<LockManager>,
+    /// Resources locked by escalation, with the mode covering the locks they replaced.
+    escalated: HashMap<ResourceId, LockMode>,
+    /// Resources whose locks were given up for an escalated lock above them, with the
+    /// strongest mode they were acquired in.
+    covered: HashMap<ResourceId, LockMode>,
+    escalations: Vec<EscalationEvent>,
+    /// Before-images of the writes, registered with the lock table from the first one on.
+    undo_log: Arc<UndoLog>,
+    undo_registered: bool,
+}
+
+impl Transaction {
+    /// A transaction taking its locks in `lock_table`.
+    pub fn new(lock_table: Arc<LockTable>, txn_id: String) -> Self {
+        let undo_log = Arc::new(UndoLog::new(txn_id.clone()));
+        Self {
+            lock_table,
+            txn_id,
+            timestamp: timestamp_nanos(),
+            state: TransactionState::Growing,
+            prevention: DeadLockPrevention::default(),
+            protocol: TwoPhaseLocking::default(),
+            escalation: LockEscalation::default(),
+            held: vec![],
+            escalated: HashMap::new(),
+            covered: HashMap::new(),
+            escalations: vec![],
+            undo_log,
+            undo_registered: false,
+        }
+    }
+
+    pub fn with_timestamp(mut self, timestamp: u128) -> Self {
+        self.timestamp = timestamp;
+        self
+    }
+
+    pub fn with_prevention(mut self, prevention: DeadLockPrevention) -> Self {
+        self.prevention = prevention;
+        self
+    }
+
+    pub fn with_protocol(mut self, protocol: TwoPhaseLocking) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    pub fn with_escalation(mut self, escalation: LockEscalation) -> Self {
+        self.escalation = escalation;
+        self
+    }
+
+    pub fn txn_id(&self) -> &str {
+        &self.txn_id
+    }
+
+    pub fn state(&self) -> TransactionState {
+        self.state
+    }
+
+    /// The locks acquired and not released yet, in acquisition order.
+    pub fn locks(&self) -> Vec<ResourceId> {
+        self.held
+            .iter()
+            .map(|lock_mgr| lock_mgr.operation().resources.clone())
+            .collect()
+    }
+
+    /// Every escalation so far, oldest first.
+    pub fn escalations(&self) -> &[EscalationEvent] {
+        &self.escalations
+    }
+
+    pub fn undo_log(&self) -> &UndoLog {
+        &self.undo_log
+    }
+
+    /// Lock `rid` and, in intention mode, each of its ancestors.
+    pub fn acquire(&mut self, rid: impl Into<ResourceId>, op_type: OpType) -> Result<Lock> {
+        let lock_mgr = self.lock_mgr(rid, op_type)?;
+        self.acquire_escalating(lock_mgr)
+    }
+
+    /// Lock `rid` and, in intention mode, each of `parents` instead of its ancestors.
+    pub fn acquire_with_parents(
+        &mut self,
+        rid: impl Into<ResourceId>,
+        parents: Vec<ResourceId>,
+        op_type: OpType,
+    ) -> Result<Lock> {
+        let operation = Operation::new(self.txn_id.clone(), rid, op_type).with_parents(parents);
+        let lock_mgr = self.operation_lock_mgr(operation)?;
+        self.acquire_escalating(lock_mgr)
+    }
+
+    /// Lock every index of the tuple `key`, so it conflicts with any overlapping tuple.
+    pub fn acquire_tuple(&mut self, key: TupleKey, op_type: OpType) -> Result<Lock> {
+        let operation = Operation::for_tuple(self.txn_id.clone(), key, op_type);
+        let lock_mgr = self.operation_lock_mgr(operation)?;
+        self.acquire_escalating(lock_mgr)
+    }
+
+    fn acquire_escalating(&mut self, lock_mgr: LockManager) -> Result<Lock> {
+        if let Some(lock) = self.covered_lock(lock_mgr.operation()) {
+            return Ok(lock);
+        }
+        let lock_rs = lock_mgr.acquire();
+        self.track_escalating(lock_mgr, lock_rs)
+    }
+
+    /// Async flavour of `acquire`, escalating the same way.
+    pub async fn acquire_async(
+        &mut self,
+        rid: impl Into<ResourceId>,
+        op_type: OpType,
+    ) -> Result<Lock> {
+        let lock_mgr = self.lock_mgr(rid, op_type)?;
+        self.acquire_escalating_async(lock_mgr).await
+    }
+
+    /// Async flavour of `acquire_tuple`.
+    pub async fn acquire_tuple_async(&mut self, key: TupleKey, op_type: OpType) -> Result<Lock> {
+        let operation = Operation::for_tuple(self.txn_id.clone(), key, op_type);
+        let lock_mgr = self.operation_lock_mgr(operation)?;
+        self.acquire_escalating_async(lock_mgr).await
+    }
+
+    async fn acquire_escalating_async(&mut self, lock_mgr: LockManager) -> Result<Lock> {
+        if let Some(lock) = self.covered_lock(lock_mgr.operation()) {
+            return Ok(lock);
+        }
+        let lock_rs = lock_mgr.acquire_async().await;
+        self.track_escalating(lock_mgr, lock_rs)
+    }
+
+    /// Lock the index range `key`, e.g. for a scan that must not see phantoms. The lock is
+    /// named after the range, see `Operation::for_range`.
+    pub fn acquire_range(&mut self, key: RangeKey, op_type: OpType) -> Result<Lock> {
+        let operation = Operation::for_range(self.txn_id.clone(), key, op_type);
+        let lock_mgr = self.operation_lock_mgr(operation)?;
+        self.acquire_escalating(lock_mgr)
+    }
+
+    /// Release the lock on `rid`, which moves the transaction into its shrinking phase.
+    pub fn release(&mut self, rid: &ResourceId) -> Result<()> {
+        self.check_active()?;
+        let pos = self
+            .held
+            .iter()
+            .position(|lock_mgr| &lock_mgr.operation().resources == rid);
+        let lock_mode = match (pos, self.covered.get(rid)) {
+            (Some(pos), _) => *OP_LOCK_MAPPING
+                .get(&self.held[pos].operation().op_type)
+                .unwrap(),
+            (None, Some(lock_mode)) => *lock_mode,
+            (None, None) => return Err(anyhow!(LockErrorCode::NoLockHeld(self.txn_id.clone()))),
+        };
+        if !self.protocol.allow_release(lock_mode) {
+            return Err(anyhow!(TransactionErrorCode::EarlyRelease(
+                self.txn_id.clone(),
+                rid.clone(),
+                self.protocol
+            )));
+        }
+        if let Some(pos) = pos {
+            let targets = self.held[pos].operation().targets();
+            if targets.iter().any(|target| self.undo_log.wrote_in(target)) {
+                return Err(anyhow!(TransactionErrorCode::WrittenRelease(
+                    self.txn_id.clone(),
+                    rid.clone()
+                )));
+            }
+        }
+        self.state = TransactionState::Shrinking;
+        let Some(pos) = pos else {
+            // Still locked by the escalated lock above it.
+            self.covered.remove(rid);
+            return Ok(());
+        };
+        let lock_mgr = self.held.remove(pos);
+        self.escalated.remove(rid);
+        lock_mgr.release()
+    }
+
+    /// Write `value` at `index` of `segment`, keeping the value it replaces for `abort`
+    /// and logging the update in `wal` first, if any. The caller holds the exclusive lock
+    /// on the tuple. Fails when the lock table aborted the transaction meanwhile, its
+    /// writes are undone already, or when `index` is not in the segment, leaving the
+    /// transaction running.
+    pub fn write_logged(
+        &mut self,
+        segment_id: &ResourceId,
+        segment: &Arc<RwLock<Segment>>,
+        wal: Option<&Arc<Wal>>,
+        index: usize,
+        value: Value,
+    ) -> Result<()> {
+        self.check_active()?;
+        if !self.undo_registered {
+            self.lock_table
+                .register_undo_log(&self.txn_id, self.undo_log.clone());
+            self.undo_registered = true;
+        }
+        if !self
+            .undo_log
+            .write(segment_id, segment, wal, index, value)?
+        {
+            let _ = self.abort();
+            return Err(anyhow!(TransactionErrorCode::NotActive(
+                self.txn_id.clone(),
+                self.state
+            )));
+        }
+        Ok(())
+    }
+
+    /// Release every lock and end the transaction. Fails, aborting it, when the lock table
+    /// aborted it meanwhile, e.g. wounded it while it was reading, or the commit could not
+    /// be logged.
+    pub fn commit(&mut self) -> Result<()> {
+        self.check_active()?;
+        let commit_rs = match self.lock_table.take_wounded(&self.txn_id) {
+            Ok(()) => self.undo_log.commit(),
+            Err(_) => Ok(false),
+        };
+        if !matches!(commit_rs, Ok(true)) {
+            let _ = self.abort();
+            commit_rs?;
+            return Err(anyhow!(TransactionErrorCode::NotActive(
+                self.txn_id.clone(),
+                self.state
+            )));
+        }
+        self.lock_table.remove_undo_log(&self.txn_id);
+        let release_rs = self.release_all();
+        self.state = TransactionState::Committed;
+        release_rs
+    }
+
+    /// Undo every write, release every lock and end the transaction, also allowed after a
+    /// failed acquire.
+    pub fn abort(&mut self) -> Result<()> {
+        if self.state == TransactionState::Committed {
+            return Err(anyhow!(TransactionErrorCode::NotActive(
+                self.txn_id.clone(),
+                self.state
+            )));
+        }
+        self.undo_log.rollback();
+        self.lock_table.remove_undo_log(&self.txn_id);
+        let release_rs = self.release_all();
+        self.state = TransactionState::Aborted;
+        release_rs
+    }
+
+    fn lock_mgr(&self, rid: impl Into<ResourceId>, op_type: OpType) -> Result<LockManager> {
+        self.operation_lock_mgr(Operation::new(self.txn_id.clone(), rid, op_type))
+    }
+
+    fn operation_lock_mgr(&self, operation: Operation) -> Result<LockManager> {
+        self.check_active()?;
+        if self.state == TransactionState::Shrinking {
+            return Err(anyhow!(TransactionErrorCode::AcquireInShrinking(
+                self.txn_id.clone(),
+                operation.resources
+            )));
+        }
+        let operation = operation.with_timestamp(self.timestamp);
+        Ok(LockManager::new(self.lock_table.clone(), operation).with_prevention(self.prevention))
+    }
+
+    /// Remember a granted lock. When the lock manager aborted the transaction (deadlock
+    /// victim, Wait-Die, Wound-Wait) its locks are gone already and so is the transaction.
+    fn track(&mut self, lock_mgr: LockManager, lock_rs: Result<Lock>) -> Result<Lock> {
+        match lock_rs {
+            Ok(lock) => {
+                self.held.push(lock_mgr);
+                Ok(lock)
+            }
+            Err(err) => {
+                if let Some(
+                    LockErrorCode::Deadlock(_, _)
+                    | LockErrorCode::WaitDieAbort(_, _)
+                    | LockErrorCode::Wounded(_, _),
+                ) = err.downcast_ref::<LockErrorCode>()
+                {
+                    let _ = self.abort();
+                }
+                Err(err)
+            }
+        }
+    }
+
+    /// The lock on the resource of `operation` when an escalated lock on its segment, or
+    /// on the one chunk it lies in, already covers it. The resource is then remembered as
+    /// covered.
+    fn covered_lock(&mut self, operation: &Operation) -> Option<Lock> {
+        let require_lock = *OP_LOCK_MAPPING.get(&operation.op_type).unwrap();
+        let covers = |rid: &ResourceId| {
+            self.escalated
+                .get(rid)
+                .is_some_and(|lock_mode| lock_mode.covers(require_lock))
+        };
+        let covered = match operation.parents.split_first() {
+            Some((segment, chunks)) => {
+                covers(segment)
+                    || chunks
+                        .iter()
+                        .any(|chunk| covers(chunk) && in_chunk(operation, chunk))
+            }
+            None => false,
+        };
+        if !covered {
+            return None;
+        }
+        self.cover(operation.resources.clone(), require_lock);
+        Some(Lock::new(
+            require_lock,
+            self.txn_id.clone(),
+            operation.resources.clone(),
+        ))
+    }
+
+    fn cover(&mut self, rid: ResourceId, lock_mode: LockMode) {
+        self.covered
+            .entry(rid)
+            .and_modify(|covered| *covered = covered.supremum(lock_mode))
+            .or_insert(lock_mode);
+    }
+
+    /// Remember a lock acquired without escalation and escalate around it.
+    fn track_escalating(&mut self, lock_mgr: LockManager, lock_rs: Result<Lock>) -> Result<Lock> {
+        let lock = self.track(lock_mgr, lock_rs)?;
+        self.escalate()?;
+        Ok(lock)
+    }
+
+    /// Escalate around the lock acquired last, whose parents are its segment followed by
+    /// its chunks: first to the chunks with too many tuples locked, then to the segment
+    /// with too many chunks locked.
+    fn escalate(&mut self) -> Result<()> {
+        let parents = match self.held.last() {
+            Some(lock_mgr) => lock_mgr.operation().parents.clone(),
+            None => return Ok(()),
+        };
+        let Some((segment, chunks)) = parents.split_first() else {
+            return Ok(());
+        };
+        for chunk in chunks {
+            let below = self.held_below(|operation| in_chunk(operation, chunk));
+            let tuples = below
+                .iter()
+                .map(|pos| &self.held[*pos].operation().resources)
+                .collect::<HashSet<_>>();
+            if tuples.len() > self.escalation.tuples_per_chunk {
+                self.escalate_to(chunk, below)?;
+            }
+        }
+        let below = self.held_below(|operation| &operation.parents[0] == segment);
+        let chunks = below
+            .iter()
+            .flat_map(|pos| {
+                let operation = self.held[*pos].operation();
+                match operation.parents.len() {
+                    1 => std::slice::from_ref(&operation.resources),
+                    _ => &operation.parents[1..],
+                }
+            })
+            .collect::<HashSet<_>>();
+        if chunks.len() > self.escalation.chunks_per_segment {
+            self.escalate_to(segment, below)?;
+        }
+        Ok(())
+    }
+
+    /// Positions of the held locks taken with parents that match `is_below`.
+    fn held_below(&self, is_below: impl Fn(&Operation) -> bool) -> Vec<usize> {
+        self.held
+            .iter()
+            .enumerate()
+            .filter(|(_, lock_mgr)| {
+                let operation = lock_mgr.operation();
+                !operation.parents.is_empty() && is_below(operation)
+            })
+            .map(|(pos, _)| pos)
+            .collect()
+    }
+
+    /// Lock `rid` in a mode covering the held locks at `below` and release those. Fails
+    /// only when the transaction got aborted meanwhile.
+    fn escalate_to(&mut self, rid: &ResourceId, below: Vec<usize>) -> Result<()> {
+        let op_type = if below
+            .iter()
+            .all(|pos| self.held[*pos].operation().op_type == OpType::Read)
+        {
+            OpType::Read
+        } else {
+            OpType::Write
+        };
+        let lock_mgr = self.lock_mgr(rid, op_type)?;
+        let lock_rs = lock_mgr.try_acquire(Duration::ZERO);
+        let lock = match self.track(lock_mgr, lock_rs) {
+            Ok(lock) => lock,
+            Err(err) if self.state == TransactionState::Aborted => return Err(err),
+            Err(_) => return Ok(()),
+        };
+        let mut released = vec![];
+        for pos in below.into_iter().rev() {
+            let lock_mgr = self.held.remove(pos);
+            let operation = lock_mgr.operation();
+            self.escalated.remove(&operation.resources);
+            self.cover(
+                operation.resources.clone(),
+                *OP_LOCK_MAPPING.get(&operation.op_type).unwrap(),
+            );
+            released.push(lock_mgr);
+        }
+        self.escalated.insert(rid.clone(), lock.lock_mode);
+        self.escalations.push(EscalationEvent {
+            txn_id: self.txn_id.clone(),
+            rid: rid.clone(),
+            lock_mode: lock.lock_mode,
+            released: released.len(),
+        });
+        LockManager::release_all(&released)
+    }
+
+    /// Give every lock back atomically, children before the locks taken earlier.
+    fn release_all(&mut self) -> Result<()> {
+        self.escalated.clear();
+        self.covered.clear();
+        let mut held = std::mem::take(&mut self.held);
+        held.reverse();
+        LockManager::release_all(&held)
+    }
+
+    fn check_active(&self) -> Result<()> {
+        match self.state {
+            TransactionState::Growing | TransactionState::Shrinking => Ok(()),
+            state => Err(anyhow!(TransactionErrorCode::NotActive(
+                self.txn_id.clone(),
+                state
+            ))),
+        }
+    }
+}
+
+/// Whether every resource `operation` locks lies in `chunk`, one of its parents. A tuple
+/// reaching into another chunk or a range covering whole chunks does not.
+fn in_chunk(operation: &Operation, chunk: &ResourceId) -> bool {
+    operation.parents[1..] == *std::slice::from_ref(chunk)
+        && operation
+            .targets()
+            .iter()
+            .all(|target| target.chunk_of().is_none_or(|of| of == chunk))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lock::LockMode;
+    use crate::lock_mgr::{DeadLockPrevention, LockErrorCode, LockManager, LockTable};
+    use crate::operation::OpType::*;
+    use crate::operation::Operation;
+    use crate::segment::{ResourceId, Segment};
+    use crate::transaction::{
+        LockEscalation, Transaction, TransactionErrorCode, TransactionState, TwoPhaseLocking,
+    };
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    pub fn test_two_phase() {
+        let lock_table = Arc::new(LockTable::new());
+        let mut txn = Transaction::new(lock_table.clone(), "txn_2pl".to_string());
+        for rid in ["txn_2pl_a", "txn_2pl_b", "txn_2pl_c"] {
+            txn.acquire(rid.to_string(), Write).unwrap();
+        }
+        assert_eq!(3, txn.locks().len());
+
+        txn.release(&"txn_2pl_a".into()).unwrap();
+        assert_eq!(TransactionState::Shrinking, txn.state());
+        let acquire_rs = txn.acquire("txn_2pl_d".to_string(), Read);
+        assert!(matches!(
+            acquire_rs.unwrap_err().downcast_ref::<TransactionErrorCode>(),
+            Some(TransactionErrorCode::AcquireInShrinking(_, rid)) if rid == "txn_2pl_d"
+        ));
+
+        txn.commit().unwrap();
+        assert_eq!(TransactionState::Committed, txn.state());
+        assert!(txn.locks().is_empty());
+        assert!(txn.commit().is_err());
+
+        // Everything was released by the commit.
+        for rid in ["txn_2pl_a", "txn_2pl_b", "txn_2pl_c"] {
+            let operation = Operation::new("txn_2pl_other".to_string(), rid.to_string(), Write);
+            let lock_mgr = LockManager::new(lock_table.clone(), operation);
+            assert!(lock_mgr.try_acquire(Duration::ZERO).is_ok());
+            lock_mgr.release().unwrap();
+        }
+    }
+
+    #[test]
+    pub fn test_reentrant_acquire() {
+        let lock_table = Arc::new(LockTable::new());
+        let rid = ResourceId::from("txn_re");
+        let mut txn = Transaction::new(lock_table.clone(), "txn_re".to_string());
+        txn.acquire(rid.clone(), Read).unwrap();
+        txn.acquire(rid.clone(), Read).unwrap();
+        txn.acquire(rid.clone(), Write).unwrap();
+        assert_eq!(3, txn.locks().len());
+
+        let operation = Operation::new("txn_re_other".to_string(), rid.clone(), Read);
+        let lock_mgr = LockManager::new(lock_table.clone(), operation);
+        txn.release(&rid).unwrap();
+        assert!(lock_mgr.try_acquire(Duration::ZERO).is_err());
+        txn.commit().unwrap();
+        assert!(lock_mgr.try_acquire(Duration::ZERO).is_ok());
+        lock_mgr.release().unwrap();
+    }
+
+    #[test]
+    pub fn test_lock_escalation() {
+        let lock_table = Arc::new(LockTable::new());
+        let ints = (0..100).collect::<Vec<_>>();
+        let segment = Segment::from_ints(10, &ints, "esc_seg".to_string());
+        let tuple = |idx: i32| (idx.to_string(), segment.parent_resources(&[idx]).unwrap());
+        let chunk_0 = segment.parent_resources(&[0]).unwrap()[1].clone();
+        let mut txn = Transaction::new(lock_table.clone(), "txn_esc".to_string()).with_escalation(
+            LockEscalation {
+                tuples_per_chunk: 3,
+                chunks_per_segment: 2,
+            },
+        );
+        for idx in 0..4 {
+            let (rid, parents) = tuple(idx);
+            txn.acquire_with_parents(rid, parents, Read).unwrap();
+        }
+        assert_eq!(1, txn.escalations().len());
+        let event = &txn.escalations()[0];
+        assert_eq!(
+            (&chunk_0, LockMode::Shared, 4),
+            (&event.rid, event.lock_mode, event.released)
+        );
+        assert_eq!(vec![chunk_0.clone()], txn.locks());
+        // Tuples below the escalated chunk are covered by it.
+        let (rid, parents) = tuple(5);
+        txn.acquire_with_parents(rid, parents, Read).unwrap();
+        assert_eq!(1, txn.locks().len());
+
+        let mut other = Transaction::new(lock_table.clone(), "txn_esc_other".to_string());
+        let (rid, parents) = tuple(6);
+        other.acquire_with_parents(rid, parents, Read).unwrap();
+        let (rid, parents) = tuple(7);
+        let writer = LockManager::new(
+            lock_table.clone(),
+            Operation::new("txn_esc_w".to_string(), rid, Write).with_parents(parents),
+        );
+        assert!(writer.try_acquire(Duration::ZERO).is_err());
+
+        // A segment lock is not granted while another transaction works in it.
+        for idx in [10, 20] {
+            let (rid, parents) = tuple(idx);
+            txn.acquire_with_parents(rid, parents, Write).unwrap();
+        }
+        assert_eq!(1, txn.escalations().len());
+        other.commit().unwrap();
+        let (rid, parents) = tuple(21);
+        txn.acquire_with_parents(rid, parents, Write).unwrap();
+        assert_eq!(2, txn.escalations().len());
+        let event = &txn.escalations()[1];
+        assert_eq!(
+            ("esc_seg", LockMode::Exclusive, 4),
+            (
+                event.rid.to_string().as_str(),
+                event.lock_mode,
+                event.released
+            )
+        );
+        assert_eq!(vec!["esc_seg".to_string()], txn.locks());
+        assert!(writer.try_acquire(Duration::ZERO).is_err());
+
+        txn.release(&"21".into()).unwrap();
+        txn.commit().unwrap();
+        assert!(writer.try_acquire(Duration::ZERO).is_ok());
+        writer.release().unwrap();
+    }
+
+    #[test]
+    pub fn test_escalation_keeps_tuples_spanning_chunks() {
+        let lock_table = Arc::new(LockTable::new());
+        let ints = (0..30).collect::<Vec<_>>();
+        let segment = Segment::from_ints(10, &ints, "esc_span_seg".to_string());
+        let chunk_0 = segment.segment_id().chunk(0, 10);
+        let mut txn = Transaction::new(lock_table.clone(), "txn_esc_span".to_string())
+            .with_escalation(LockEscalation {
+                tuples_per_chunk: 1,
+                chunks_per_segment: 8,
+            });
+        let writer = |index: i32| {
+            LockManager::new(
+                lock_table.clone(),
+                Operation::for_tuple(
+                    "txn_esc_span_w".to_string(),
+                    segment.tuple_key(&[index]).unwrap(),
+                    Write,
+                ),
+            )
+        };
+        txn.acquire_tuple(segment.tuple_key(&[1]).unwrap(), Write)
+            .unwrap();
+        // Index 10 is not in the chunk, so the tuple does not count towards it.
+        txn.acquire_tuple(segment.tuple_key(&[9, 10]).unwrap(), Write)
+            .unwrap();
+        assert!(txn.escalations().is_empty());
+        txn.acquire_tuple(segment.tuple_key(&[2]).unwrap(), Write)
+            .unwrap();
+        assert_eq!(1, txn.escalations().len());
+        assert_eq!(
+            (&chunk_0, 2),
+            (&txn.escalations()[0].rid, txn.escalations()[0].released)
+        );
+        // The tuple keeps its lock on index 10 and is not covered by the chunk.
+        assert_eq!(2, txn.locks().len());
+        for index in [3, 9, 10] {
+            assert!(writer(index).try_acquire(Duration::ZERO).is_err());
+        }
+        txn.acquire_tuple(segment.tuple_key(&[8, 11]).unwrap(), Write)
+            .unwrap();
+        assert_eq!(3, txn.locks().len());
+        txn.commit().unwrap();
+        let writer = writer(10);
+        writer.try_acquire(Duration::ZERO).unwrap();
+        writer.release().unwrap();
+    }
+
+    #[test]
+    pub fn test_escalated_release_checks_protocol() {
+        let lock_table = Arc::new(LockTable::new());
+        let chunk = ResourceId::segment("esc_rig_seg").chunk(0, 10);
+        let mut txn = Transaction::new(lock_table, "txn_esc_rig".to_string())
+            .with_protocol(TwoPhaseLocking::Rigorous)
+            .with_escalation(LockEscalation {
+                tuples_per_chunk: 1,
+                chunks_per_segment: 8,
+            });
+        for idx in 0..2 {
+            txn.acquire(chunk.tuple(idx), Read).unwrap();
+        }
+        assert_eq!(vec![chunk.clone()], txn.locks());
+        // Covered by the chunk lock, the tuple is still held until commit.
+        assert!(matches!(
+            txn.release(&chunk.tuple(0))
+                .unwrap_err()
+                .downcast_ref::<TransactionErrorCode>(),
+            Some(TransactionErrorCode::EarlyRelease(
+                _,
+                _,
+                TwoPhaseLocking::Rigorous
+            ))
+        ));
+        assert_eq!(TransactionState::Growing, txn.state());
+        txn.commit().unwrap();
+    }
+
+    #[tokio::test]
+    pub async fn test_async_lock_escalation() {
+        let lock_table = Arc::new(LockTable::new());
+        let chunk = ResourceId::segment("esc_async_seg").chunk(0, 10);
+        let mut txn = Transaction::new(lock_table, "txn_esc_async".to_string()).with_escalation(
+            LockEscalation {
+                tuples_per_chunk: 2,
+                chunks_per_segment: 8,
+            },
+        );
+        for idx in 0..4 {
+            txn.acquire_async(chunk.tuple(idx), Write).await.unwrap();
+        }
+        assert_eq!(1, txn.escalations().len());
+        assert_eq!(
+            (&chunk, LockMode::Exclusive, 3),
+            (
+                &txn.escalations()[0].rid,
+                txn.escalations()[0].lock_mode,
+                txn.escalations()[0].released
+            )
+        );
+        assert_eq!(vec![chunk], txn.locks());
+        txn.commit().unwrap();
+    }
+
+    #[test]
+    pub fn test_overlapping_tuples() {
+        let lock_table = Arc::new(LockTable::new());
+        let ints = (0..30).collect::<Vec<_>>();
+        let segment = Segment::from_ints(10, &ints, "txn_tuple_seg".to_string());
+        let mut reader = Transaction::new(lock_table.clone(), "txn_tuple_r".to_string());
+        let lock = reader
+            .acquire_tuple(segment.tuple_key(&[1, 2, 3]).unwrap(), Read)
+            .unwrap();
+        assert_eq!("txn_tuple_seg/(1,2,3)", lock.rid);
+
+        let writer = |index: &[i32]| {
+            LockManager::new(
+                lock_table.clone(),
+                Operation::for_tuple(
+                    "txn_tuple_w".to_string(),
+                    segment.tuple_key(index).unwrap(),
+                    Write,
+                ),
+            )
+        };
+        assert!(writer(&[2]).try_acquire(Duration::ZERO).is_err());
+        assert!(writer(&[3, 11]).try_acquire(Duration::ZERO).is_err());
+        let disjoint = writer(&[4, 11]);
+        disjoint.acquire().unwrap();
+        disjoint.release().unwrap();
+
+        reader.release(&lock.rid).unwrap();
+        let overlapping = writer(&[2, 3]);
+        overlapping.acquire().unwrap();
+        overlapping.release().unwrap();
+    }
+
+    #[test]
+    pub fn test_range_lock_prevents_phantoms() {
+        let lock_table = Arc::new(LockTable::new());
+        let ints = (0..400).collect::<Vec<_>>();
+        let segment = Segment::from_ints(10, &ints, "txn_range_seg".to_string());
+        let mut scan = Transaction::new(lock_table.clone(), "txn_scan".to_string())
+            .with_protocol(TwoPhaseLocking::Strict);
+        let lock = scan
+            .acquire_range(segment.range_key((95, 200)).unwrap(), Read)
+            .unwrap();
+        assert_eq!("txn_range_seg[95,200)", lock.rid);
+
+        // Writes lock their tuple as usual, inside the range they are phantoms.
+        let writer = |index: i32| {
+            LockManager::new(
+                lock_table.clone(),
+                Operation::for_tuple(
+                    "txn_range_w".to_string(),
+                    segment.tuple_key(&[index]).unwrap(),
+                    Write,
+                ),
+            )
+        };
+        for index in [95, 99, 150, 199] {
+            assert!(writer(index).try_acquire(Duration::ZERO).is_err());
+        }
+        for index in [94, 200] {
+            let writer = writer(index);
+            writer.acquire().unwrap();
+            writer.release().unwrap();
+        }
+        // Nor can the segment be locked as a whole, e.g. by escalation.
+        let segment_writer = LockManager::new(
+            lock_table.clone(),
+            Operation::new("txn_range_seg_w".to_string(), segment.segment_id(), Write),
+        );
+        assert!(segment_writer.try_acquire(Duration::ZERO).is_err());
+        let mut other_scan = Transaction::new(lock_table.clone(), "txn_scan_other".to_string());
+        other_scan
+            .acquire_range(segment.range_key((0, 400)).unwrap(), Read)
+            .unwrap();
+        other_scan.commit().unwrap();
+
+        let mut range_writer = Transaction::new(lock_table.clone(), "txn_range_rw".to_string())
+            .with_protocol(TwoPhaseLocking::Strict);
+        let lock = range_writer
+            .acquire_range(segment.range_key((300, 310)).unwrap(), Write)
+            .unwrap();
+        assert!(matches!(
+            range_writer
+                .release(&lock.rid)
+                .unwrap_err()
+                .downcast_ref::<TransactionErrorCode>(),
+            Some(TransactionErrorCode::EarlyRelease(
+                _,
+                _,
+                TwoPhaseLocking::Strict
+            ))
+        ));
+        range_writer.abort().unwrap();
+
+        // A write queued behind the scan is granted with its commit.
+        let blocked = {
+            let writer = writer(150);
+            std::thread::spawn(move || writer.acquire().map(|_| writer))
+        };
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(!blocked.is_finished());
+        scan.commit().unwrap();
+        blocked.join().unwrap().unwrap().release().unwrap();
+        segment_writer.try_acquire(Duration::ZERO).unwrap();
+        segment_writer.release().unwrap();
+    }
+
+    #[test]
+    pub fn test_range_dead_lock() {
+        let lock_table = Arc::new(LockTable::new());
+        let ints = (0..100).collect::<Vec<_>>();
+        let segment = Segment::from_ints(10, &ints, "txn_range_dl".to_string());
+        let mut scan = Transaction::new(lock_table.clone(), "txn_range_dl_scan".to_string());
+        let mut writer = Transaction::new(lock_table.clone(), "txn_range_dl_w".to_string());
+        scan.acquire_range(segment.range_key((0, 10)).unwrap(), Read)
+            .unwrap();
+        writer
+            .acquire_tuple(segment.tuple_key(&[50]).unwrap(), Write)
+            .unwrap();
+
+        let writer_join = {
+            let key = segment.tuple_key(&[5]).unwrap();
+            std::thread::spawn(move || (writer.acquire_tuple(key, Write), writer))
+        };
+        while !lock_table
+            .dead_lock_detector()
+            .has_outgoing("txn_range_dl_w")
+        {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        let scan_join = {
+            let key = segment.range_key((40, 60)).unwrap();
+            std::thread::spawn(move || (scan.acquire_range(key, Read), scan))
+        };
+        // Range waits are part of the wait-for graph like any other.
+        while lock_table.detect_dead_locks().is_empty() {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(vec!["txn_range_dl_w"], lock_table.resolve_dead_locks());
+        let (acquire_rs, writer) = writer_join.join().unwrap();
+        assert!(matches!(
+            acquire_rs.unwrap_err().downcast_ref::<LockErrorCode>(),
+            Some(LockErrorCode::Deadlock(_, _))
+        ));
+        assert_eq!(TransactionState::Aborted, writer.state());
+        let (acquire_rs, mut scan) = scan_join.join().unwrap();
+        acquire_rs.unwrap();
+        scan.commit().unwrap();
+    }
+
+    #[test]
+    pub fn test_abort_by_lock_manager() {
+        let lock_table = Arc::new(LockTable::new());
+        let mut old = Transaction::new(lock_table.clone(), "txn_old".to_string())
+            .with_timestamp(1)
+            .with_prevention(DeadLockPrevention::WaitDie);
+        let mut young = Transaction::new(lock_table.clone(), "txn_young".to_string())
+            .with_timestamp(2)
+            .with_prevention(DeadLockPrevention::WaitDie);
+        young.acquire("txn_abort_b".to_string(), Write).unwrap();
+        old.acquire("txn_abort_a".to_string(), Write).unwrap();
+
+        let acquire_rs = young.acquire("txn_abort_a".to_string(), Read);
+        assert!(matches!(
+            acquire_rs.unwrap_err().downcast_ref::<LockErrorCode>(),
+            Some(LockErrorCode::WaitDieAbort(_, _))
+        ));
+        assert_eq!(TransactionState::Aborted, young.state());
+        assert!(young.locks().is_empty());
+
+        // The lock young held is free again.
+        let operation = Operation::new(
+            "txn_abort_other".to_string(),
+            "txn_abort_b".to_string(),
+            Write,
+        );
+        let lock_mgr = LockManager::new(lock_table.clone(), operation);
+        assert!(lock_mgr.try_acquire(Duration::ZERO).is_ok());
+        lock_mgr.release().unwrap();
+        old.commit().unwrap();
+    }
+
+    #[test]
+    pub fn test_wounded_reader_commit_aborts() {
+        let lock_table = Arc::new(LockTable::new());
+        let mut old = Transaction::new(lock_table.clone(), "txn_wound_old".to_string())
+            .with_timestamp(1)
+            .with_prevention(DeadLockPrevention::WoundWait);
+        let mut young = Transaction::new(lock_table.clone(), "txn_wound_young".to_string())
+            .with_timestamp(2)
+            .with_prevention(DeadLockPrevention::WoundWait);
+        young.acquire("txn_wound_r".to_string(), Read).unwrap();
+        old.acquire("txn_wound_r".to_string(), Write).unwrap();
+
+        // The read no longer holds, the read-only transaction must not commit.
+        assert!(matches!(
+            young
+                .commit()
+                .unwrap_err()
+                .downcast_ref::<TransactionErrorCode>(),
+            Some(TransactionErrorCode::NotActive(
+                _,
+                TransactionState::Aborted
+            ))
+        ));
+        assert_eq!(TransactionState::Aborted, young.state());
+        old.commit().unwrap();
+        assert_eq!(TransactionState::Committed, old.state());
+    }
+
+    fn assert_early_release(txn: &mut Transaction, rid: &str) {
+        let release_rs = txn.release(&rid.into());
+        assert!(matches!(
+            release_rs.unwrap_err().downcast_ref::<TransactionErrorCode>(),
+            Some(TransactionErrorCode::EarlyRelease(_, early_rid, _)) if early_rid == rid
+        ));
+    }
+
+    #[test]
+    pub fn test_strict_two_phase() {
+        let lock_table = Arc::new(LockTable::new());
+        let mut txn = Transaction::new(lock_table.clone(), "txn_strict".to_string())
+            .with_protocol(TwoPhaseLocking::Strict);
+        txn.acquire("txn_strict_r".to_string(), Read).unwrap();
+        txn.acquire("txn_strict_w".to_string(), Write).unwrap();
+
+        assert_early_release(&mut txn, "txn_strict_w");
+        assert_eq!(TransactionState::Growing, txn.state());
+        txn.release(&"txn_strict_r".into()).unwrap();
+        assert_eq!(TransactionState::Shrinking, txn.state());
+        assert_eq!(vec!["txn_strict_w".to_string()], txn.locks());
+
+        txn.commit().unwrap();
+        let operation = Operation::new(
+            "txn_strict_other".to_string(),
+            "txn_strict_w".to_string(),
+            Write,
+        );
+        let lock_mgr = LockManager::new(lock_table.clone(), operation);
+        assert!(lock_mgr.try_acquire(Duration::ZERO).is_ok());
+        lock_mgr.release().unwrap();
+    }
+
+    #[test]
+    pub fn test_rigorous_two_phase() {
+        let lock_table = Arc::new(LockTable::new());
+        let mut txn = Transaction::new(lock_table.clone(), "txn_rigorous".to_string())
+            .with_protocol(TwoPhaseLocking::Rigorous);
+        txn.acquire("txn_rigorous_r".to_string(), Read).unwrap();
+        txn.acquire("txn_rigorous_w".to_string(), Write).unwrap();
+        assert_early_release(&mut txn, "txn_rigorous_r");
+        assert_early_release(&mut txn, "txn_rigorous_w");
+        assert_eq!(2, txn.locks().len());
+
+        txn.abort().unwrap();
+        assert_eq!(TransactionState::Aborted, txn.state());
+        for rid in ["txn_rigorous_r", "txn_rigorous_w"] {
+            let operation =
+                Operation::new("txn_rigorous_other".to_string(), rid.to_string(), Write);
+            let lock_mgr = LockManager::new(lock_table.clone(), operation);
+            assert!(lock_mgr.try_acquire(Duration::ZERO).is_ok());
+            lock_mgr.release().unwrap();
+        }
+    }
+}
@@ -0,0 +1,214 @@
+use crate::segment::{ResourceId, Segment};
+use crate::value::Value;
+use crate::wal::{LogRecord, Lsn, Wal};
+use anyhow::Result;
+use parking_lot::{Mutex, RwLock};
+use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
+
+/// The value `before` at `index` of `segment`, overwritten by a transaction.
+#[derive(Clone)]
+pub struct UndoRecord {
+    pub segment_id: ResourceId,
+    pub index: usize,
+    pub before: Value,
+    segment: Arc<RwLock<Segment>>,
+    /// The log of the segment and the LSN preceding the update in it.
+    wal: Option<(Arc<Wal>, Lsn)>,
+}
+
+impl Debug for UndoRecord {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UndoRecord")
+            .field("segment_id", &self.segment_id)
+            .field("index", &self.index)
+            .field("before", &self.before)
+            .finish()
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Hash)]
+pub enum UndoState {
+    #[default]
+    Active,
+    Committed,
+    RolledBack,
+}
+
+#[derive(Debug, Default)]
+struct UndoEntries {
+    state: UndoState,
+    records: Vec<UndoRecord>,
+    /// Every log written to with the LSN of the last record of the transaction there.
+    wal_lsns: Vec<(Arc<Wal>, Lsn)>,
+}
+
+impl UndoEntries {
+    fn last_lsn(&mut self, wal: &Arc<Wal>) -> Option<&mut Lsn> {
+        self.wal_lsns
+            .iter_mut()
+            .find(|(logged, _)| Arc::ptr_eq(logged, wal))
+            .map(|(_, lsn)| lsn)
+    }
+}
+
+/// Before-images of every value a transaction wrote, newest last. A write and its record
+/// are applied together under the log's mutex, and so is `rollback`, so an abort from
+/// another thread (deadlock victim, Wound-Wait) never races a write of the transaction:
+/// once rolled back every further write is refused.
+///
+/// The lock table rolls the log back before it releases the locks of an aborted
+/// transaction, no other transaction ever sees a value about to be undone.
+///
+/// Writes to a segment with a `Wal` are logged there first, the transaction's records
+/// start with a begin record and end with a flushed commit or an abort record once every
+/// update is compensated.
+#[derive(Debug, Default)]
+pub struct UndoLog {
+    txn_id: String,
+    entries: Mutex<UndoEntries>,
+}
+
+impl UndoLog {
+    pub fn new(txn_id: String) -> Self {
+        Self {
+            txn_id,
+            entries: Mutex::default(),
+        }
+    }
+
+    pub fn state(&self) -> UndoState {
+        self.entries.lock().state
+    }
+
+    pub fn records(&self) -> Vec<UndoRecord> {
+        self.entries.lock().records.clone()
+    }
+
+    /// Whether a value logged for undo lies in `rid`.
+    pub fn wrote_in(&self, rid: &ResourceId) -> bool {
+        self.entries
+            .lock()
+            .records
+            .iter()
+            .any(|record| rid.contains_index(&record.segment_id, record.index))
+    }
+
+    /// Write `value` at `index` of `segment` and log the value it replaces, in `wal` as
+    /// well if any. Returns false, leaving the segment untouched, once the log is no
+    /// longer active, and fails with `SegmentError::IndexOutOfRange` when `index` is not
+    /// in the segment.
+    pub fn write(
+        &self,
+        segment_id: &ResourceId,
+        segment: &Arc<RwLock<Segment>>,
+        wal: Option<&Arc<Wal>>,
+        index: usize,
+        value: Value,
+    ) -> Result<bool> {
+        let mut entries = self.entries.lock();
+        if entries.state != UndoState::Active {
+            return Ok(false);
+        }
+        let mut data = segment.write();
+        let wal = match wal {
+            Some(wal) => {
+                let before = data.get_value(index)?;
+                let prev_lsn = match entries.last_lsn(wal) {
+                    Some(lsn) => *lsn,
+                    None => {
+                        let lsn = wal.append(&self.txn_id, 0, LogRecord::Begin)?;
+                        entries.wal_lsns.push((wal.clone(), lsn));
+                        lsn
+                    }
+                };
+                let lsn = wal.append(
+                    &self.txn_id,
+                    prev_lsn,
+                    LogRecord::Update {
+                        segment_id: segment_id.to_string(),
+                        index,
+                        before,
+                        after: value.clone(),
+                    },
+                )?;
+                *entries.last_lsn(wal).unwrap() = lsn;
+                Some((wal.clone(), prev_lsn))
+            }
+            None => None,
+        };
+        let before = data.replace_value(index, value)?;
+        entries.records.push(UndoRecord {
+            segment_id: segment_id.clone(),
+            index,
+            before,
+            segment: segment.clone(),
+            wal,
+        });
+        Ok(true)
+    }
+
+    /// Keep the writes once the commit records are on disk, returns false when the log
+    /// was rolled back already.
+    pub fn commit(&self) -> Result<bool> {
+        let mut entries = self.entries.lock();
+        match entries.state {
+            UndoState::RolledBack => Ok(false),
+            _ => {
+                for (wal, lsn) in entries.wal_lsns.iter() {
+                    wal.append(&self.txn_id, *lsn, LogRecord::Commit)?;
+                    wal.flush()?;
+                }
+                entries.state = UndoState::Committed;
+                entries.records.clear();
+                entries.wal_lsns.clear();
+                Ok(true)
+            }
+        }
+    }
+
+    /// Restore every before-image, newest first, and return how many were restored. Does
+    /// nothing for a committed or already rolled back log.
+    ///
+    /// Failing to log a CLR or the abort record is ignored, without the abort record
+    /// recovery undoes whatever was not compensated in the log.
+    pub fn rollback(&self) -> usize {
+        let mut entries = self.entries.lock();
+        if entries.state != UndoState::Active {
+            return 0;
+        }
+        entries.state = UndoState::RolledBack;
+        let records = std::mem::take(&mut entries.records);
+        let restored = records.len();
+        let mut compensated = true;
+        for record in records.into_iter().rev() {
+            if let Some((wal, undo_next_lsn)) = &record.wal {
+                let last_lsn = entries.last_lsn(wal).unwrap();
+                match wal.append(
+                    &self.txn_id,
+                    *last_lsn,
+                    LogRecord::Compensation {
+                        segment_id: record.segment_id.to_string(),
+                        index: record.index,
+                        value: record.before.clone(),
+                        undo_next_lsn: *undo_next_lsn,
+                    },
+                ) {
+                    Ok(lsn) => *last_lsn = lsn,
+                    Err(_) => compensated = false,
+                }
+            }
+            // Written before, the index is in the segment.
+            let _ = record
+                .segment
+                .write()
+                .update_value(record.index, record.before);
+        }
+        for (wal, lsn) in std::mem::take(&mut entries.wal_lsns) {
+            if compensated {
+                let _ = wal.append(&self.txn_id, lsn, LogRecord::Abort);
+            }
+        }
+        restored
+    }
+}
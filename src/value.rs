@@ -0,0 +1,188 @@
+use crate::codec::{Decoder, Encoder};
+use anyhow::{anyhow, Result};
+use std::hash::{Hash, Hasher};
+use thiserror::Error;
+
+#[derive(Error, Debug, Clone, Eq, PartialEq)]
+pub enum ValueErrorCode {
+    #[error("Unknown value kind {0}")]
+    UnknownKind(u8),
+}
+
+/// A value stored at one index of a segment. Payloads of any size can be modelled with
+/// `Bytes` or `String`, every read copies the value out of the segment and every write
+/// moves one in.
+///
+/// Floats compare and hash by their bits, so `NaN` equals itself and `-0.0` does not
+/// equal `0.0`.
+#[derive(Debug, Clone)]
+pub enum Value {
+    Int(i64),
+    Float(f64),
+    Bytes(Vec<u8>),
+    String(String),
+}
+
+impl Value {
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Value::Int(int) => Some(*int),
+            _ => None,
+        }
+    }
+
+    /// Bytes taken by the payload, the size of the number for ints and floats.
+    pub fn size(&self) -> usize {
+        match self {
+            Value::Int(_) | Value::Float(_) => 8,
+            Value::Bytes(bytes) => bytes.len(),
+            Value::String(string) => string.len(),
+        }
+    }
+
+    pub(crate) fn encode(&self, encoder: &mut Encoder) {
+        match self {
+            Value::Int(int) => encoder.u8(0).u64(*int as u64),
+            Value::Float(float) => encoder.u8(1).u64(float.to_bits()),
+            Value::Bytes(bytes) => encoder.u8(2).bytes(bytes),
+            Value::String(string) => encoder.u8(3).str(string),
+        };
+    }
+
+    pub(crate) fn decode(decoder: &mut Decoder) -> Result<Self> {
+        Ok(match decoder.u8()? {
+            0 => Value::Int(decoder.u64()? as i64),
+            1 => Value::Float(f64::from_bits(decoder.u64()?)),
+            2 => {
+                let len = decoder.u32()? as usize;
+                Value::Bytes(decoder.bytes(len)?.to_vec())
+            }
+            3 => Value::String(decoder.str()?),
+            kind => return Err(anyhow!(ValueErrorCode::UnknownKind(kind))),
+        })
+    }
+
+    fn kind(&self) -> u8 {
+        match self {
+            Value::Int(_) => 0,
+            Value::Float(_) => 1,
+            Value::Bytes(_) => 2,
+            Value::String(_) => 3,
+        }
+    }
+}
+
+impl Default for Value {
+    fn default() -> Self {
+        Value::Int(0)
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Int(a), Value::Int(b)) => a == b,
+            (Value::Float(a), Value::Float(b)) => a.to_bits() == b.to_bits(),
+            (Value::Bytes(a), Value::Bytes(b)) => a == b,
+            (Value::String(a), Value::String(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Value {}
+
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.kind().hash(state);
+        match self {
+            Value::Int(int) => int.hash(state),
+            Value::Float(float) => float.to_bits().hash(state),
+            Value::Bytes(bytes) => bytes.hash(state),
+            Value::String(string) => string.hash(state),
+        }
+    }
+}
+
+impl PartialEq<i32> for Value {
+    fn eq(&self, other: &i32) -> bool {
+        self.as_int() == Some(*other as i64)
+    }
+}
+
+impl PartialEq<Value> for i32 {
+    fn eq(&self, other: &Value) -> bool {
+        other == self
+    }
+}
+
+impl From<i32> for Value {
+    fn from(int: i32) -> Self {
+        Value::Int(int as i64)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(int: i64) -> Self {
+        Value::Int(int)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(float: f64) -> Self {
+        Value::Float(float)
+    }
+}
+
+impl From<Vec<u8>> for Value {
+    fn from(bytes: Vec<u8>) -> Self {
+        Value::Bytes(bytes)
+    }
+}
+
+impl From<String> for Value {
+    fn from(string: String) -> Self {
+        Value::String(string)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(string: &str) -> Self {
+        Value::String(string.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::codec::{Decoder, Encoder};
+    use crate::value::Value;
+    use std::collections::HashSet;
+
+    #[test]
+    pub fn test_value_codec() {
+        let values = vec![
+            Value::from(-7),
+            Value::from(i64::MAX),
+            Value::from(f64::NAN),
+            Value::from(-0.0),
+            Value::from(vec![0_u8; 300]),
+            Value::from("payload"),
+        ];
+        let mut encoder = Encoder::new();
+        values.iter().for_each(|value| value.encode(&mut encoder));
+        let bytes = encoder.finish();
+        let mut decoder = Decoder::new(&bytes);
+        let decoded = values
+            .iter()
+            .map(|_| Value::decode(&mut decoder).unwrap())
+            .collect::<Vec<_>>();
+        assert!(decoder.is_empty());
+        assert_eq!(values, decoded);
+
+        assert_eq!(-7, values[0]);
+        assert_ne!(Value::from(0.0), values[3]);
+        assert_ne!(Value::from(1), Value::from(1.0));
+        assert_eq!(300, values[4].size());
+        assert_eq!(values.len(), values.iter().collect::<HashSet<_>>().len());
+    }
+}
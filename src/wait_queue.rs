@@ -0,0 +1,120 @@
+use crate::lock::Lock;
+use crate::lock_mgr::LockErrorCode;
+use crate::operation::Operation;
+use parking_lot::{Condvar, Mutex};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) enum WaitStatus {
+    Waiting,
+    Granted(Lock),
+    Aborted(LockErrorCode),
+}
+
+/// Parking spot of a single blocked request. The status is flipped by the releasing
+/// operation while it still holds the lock table latch, so a grant is never lost.
+/// Threads park on `cond`, async tasks on `notify`.
+#[derive(Debug)]
+pub(crate) struct LockWaiter {
+    status: Mutex<WaitStatus>,
+    cond: Condvar,
+    notify: Notify,
+}
+
+impl Default for LockWaiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LockWaiter {
+    pub fn new() -> Self {
+        Self {
+            status: Mutex::new(WaitStatus::Waiting),
+            cond: Condvar::new(),
+            notify: Notify::new(),
+        }
+    }
+
+    pub fn status(&self) -> WaitStatus {
+        self.status.lock().clone()
+    }
+
+    pub fn grant(&self, lock: Lock) {
+        self.finish(WaitStatus::Granted(lock));
+    }
+
+    /// Wake the waiter with an error instead of the lock, e.g. as a deadlock victim.
+    pub fn abort(&self, err: LockErrorCode) {
+        self.finish(WaitStatus::Aborted(err));
+    }
+
+    fn finish(&self, new_status: WaitStatus) {
+        let mut status = self.status.lock();
+        *status = new_status;
+        self.cond.notify_one();
+        self.notify.notify_one();
+    }
+
+    /// Yield to the runtime until the request is granted or aborted.
+    pub async fn wait_async(&self) -> WaitStatus {
+        loop {
+            let notified = self.notify.notified();
+            let status = self.status();
+            if status != WaitStatus::Waiting {
+                return status;
+            }
+            notified.await;
+        }
+    }
+
+    /// Park the current thread until the request is granted or aborted. Returns
+    /// `Waiting` when the timeout elapsed first, the caller is then responsible for
+    /// leaving the queue.
+    pub fn wait(&self, timeout: Option<Duration>) -> WaitStatus {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut status = self.status.lock();
+        while *status == WaitStatus::Waiting {
+            match deadline {
+                Some(deadline) => {
+                    if self.cond.wait_until(&mut status, deadline).timed_out() {
+                        break;
+                    }
+                }
+                None => self.cond.wait(&mut status),
+            }
+        }
+        status.clone()
+    }
+}
+
+/// A pending lock request sitting in the FIFO queue of a resource.
+#[derive(Debug, Clone)]
+pub(crate) struct LockRequest {
+    pub operation: Operation,
+    pub lock: Lock,
+    pub waiter: Arc<LockWaiter>,
+    /// The operation already holds a weaker lock on the resource and waits to convert it.
+    pub upgrade: bool,
+}
+
+impl LockRequest {
+    pub fn new(operation: Operation, lock: Lock) -> Self {
+        Self {
+            operation,
+            lock,
+            waiter: Arc::new(LockWaiter::new()),
+            upgrade: false,
+        }
+    }
+
+    /// Request to convert the lock the operation holds into `lock`.
+    pub fn upgrade(operation: Operation, lock: Lock) -> Self {
+        Self {
+            upgrade: true,
+            ..Self::new(operation, lock)
+        }
+    }
+}
@@ -0,0 +1,522 @@
+use crate::codec::{crc32, Decoder, Encoder};
+use crate::segment::Segment;
+use crate::value::Value;
+use anyhow::{anyhow, Result};
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// Log sequence number, records are numbered from 1 on. 0 stands for no record.
+pub type Lsn = u64;
+
+#[derive(Error, Debug, Clone, Eq, PartialEq)]
+pub enum WalErrorCode {
+    #[error("Unknown record kind {1} at LSN {0}")]
+    UnknownRecord(Lsn, u8),
+    #[error("Record LSN {0} belongs to segment {1}, not to the recovered one")]
+    ForeignSegment(Lsn, String),
+    #[error("Record LSN {0} updates index {1} outside of the segment")]
+    IndexOutOfRange(Lsn, usize),
+}
+
+/// What a log record did. Updates carry both images: the after-image to redo and the
+/// before-image to undo. A compensation log record (CLR) is written for every update
+/// undone, it is only ever redone and points at the next record of its transaction still
+/// to undo, so a rollback interrupted by a crash resumes where it stopped.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum LogRecord {
+    Begin,
+    Update {
+        segment_id: String,
+        index: usize,
+        before: Value,
+        after: Value,
+    },
+    Compensation {
+        segment_id: String,
+        index: usize,
+        value: Value,
+        undo_next_lsn: Lsn,
+    },
+    Commit,
+    Abort,
+}
+
+/// A record of `txn_id` whose previous record is `prev_lsn`.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct LogEntry {
+    pub lsn: Lsn,
+    pub txn_id: String,
+    pub prev_lsn: Lsn,
+    pub record: LogRecord,
+}
+
+impl LogEntry {
+    fn encode(&self) -> Vec<u8> {
+        let mut encoder = Encoder::new();
+        encoder.u64(self.lsn).str(&self.txn_id).u64(self.prev_lsn);
+        match &self.record {
+            LogRecord::Begin => encoder.u8(0),
+            LogRecord::Update {
+                segment_id,
+                index,
+                before,
+                after,
+            } => {
+                encoder.u8(1).str(segment_id).u64(*index as u64);
+                before.encode(&mut encoder);
+                after.encode(&mut encoder);
+                &mut encoder
+            }
+            LogRecord::Compensation {
+                segment_id,
+                index,
+                value,
+                undo_next_lsn,
+            } => {
+                encoder.u8(2).str(segment_id).u64(*index as u64);
+                value.encode(&mut encoder);
+                encoder.u64(*undo_next_lsn)
+            }
+            LogRecord::Commit => encoder.u8(3),
+            LogRecord::Abort => encoder.u8(4),
+        };
+        encoder.finish()
+    }
+
+    fn decode(payload: &[u8]) -> Result<Self> {
+        let mut decoder = Decoder::new(payload);
+        let lsn = decoder.u64()?;
+        let txn_id = decoder.str()?;
+        let prev_lsn = decoder.u64()?;
+        let record = match decoder.u8()? {
+            0 => LogRecord::Begin,
+            1 => LogRecord::Update {
+                segment_id: decoder.str()?,
+                index: decoder.u64()? as usize,
+                before: Value::decode(&mut decoder)?,
+                after: Value::decode(&mut decoder)?,
+            },
+            2 => LogRecord::Compensation {
+                segment_id: decoder.str()?,
+                index: decoder.u64()? as usize,
+                value: Value::decode(&mut decoder)?,
+                undo_next_lsn: decoder.u64()?,
+            },
+            3 => LogRecord::Commit,
+            4 => LogRecord::Abort,
+            kind => return Err(anyhow!(WalErrorCode::UnknownRecord(lsn, kind))),
+        };
+        Ok(Self {
+            lsn,
+            txn_id,
+            prev_lsn,
+            record,
+        })
+    }
+}
+
+/// What `Wal::recover` found and did.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Recovery {
+    pub segment: Segment,
+    /// Updates and CLRs applied again to the snapshot.
+    pub redone: usize,
+    /// Updates of unfinished transactions undone.
+    pub undone: usize,
+    pub committed: Vec<String>,
+    /// Unfinished transactions, rolled back by the recovery.
+    pub losers: Vec<String>,
+}
+
+#[derive(Debug)]
+struct WalFile {
+    writer: BufWriter<File>,
+    next_lsn: Lsn,
+}
+
+/// Write-ahead log of the transactional updates of one segment in a local file. Every
+/// record is framed by its length and CRC-32, a torn record at the tail, left by a crash
+/// in the middle of a write, ends the log.
+///
+/// An update is logged before the segment is changed and a commit is flushed to disk
+/// before it is reported, so the segment can be rebuilt by `recover` from the snapshot
+/// it had when the log was created plus the log.
+#[derive(Debug)]
+pub struct Wal {
+    path: PathBuf,
+    file: Mutex<WalFile>,
+}
+
+impl Wal {
+    /// A new empty log at `path`, replacing any file there.
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = File::create(&path)?;
+        Ok(Self {
+            path,
+            file: Mutex::new(WalFile {
+                writer: BufWriter::new(file),
+                next_lsn: 1,
+            }),
+        })
+    }
+
+    /// The log at `path`, cut after its last intact record to append new ones.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let (entries, valid_len) = Self::read_file(&path)?;
+        let file = OpenOptions::new().append(true).open(&path)?;
+        file.set_len(valid_len)?;
+        Ok(Self {
+            path,
+            file: Mutex::new(WalFile {
+                writer: BufWriter::new(file),
+                next_lsn: entries.last().map_or(1, |entry| entry.lsn + 1),
+            }),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Append `record` of `txn_id` and return its LSN. Buffered until `flush`.
+    pub fn append(&self, txn_id: &str, prev_lsn: Lsn, record: LogRecord) -> Result<Lsn> {
+        let mut file = self.file.lock();
+        let entry = LogEntry {
+            lsn: file.next_lsn,
+            txn_id: txn_id.to_string(),
+            prev_lsn,
+            record,
+        };
+        let payload = entry.encode();
+        let frame = Encoder::new()
+            .u32(payload.len() as u32)
+            .u32(crc32(&payload))
+            .finish();
+        file.writer.write_all(&frame)?;
+        file.writer.write_all(&payload)?;
+        file.next_lsn += 1;
+        Ok(entry.lsn)
+    }
+
+    /// Write the buffered records through to the disk.
+    pub fn flush(&self) -> Result<()> {
+        let mut file = self.file.lock();
+        file.writer.flush()?;
+        file.writer.get_ref().sync_data()?;
+        Ok(())
+    }
+
+    /// Every intact record, the buffered ones included.
+    pub fn entries(&self) -> Result<Vec<LogEntry>> {
+        self.file.lock().writer.flush()?;
+        Ok(Self::read_file(&self.path)?.0)
+    }
+
+    /// The intact records of the file at `path` and their length in bytes.
+    fn read_file(path: &Path) -> Result<(Vec<LogEntry>, u64)> {
+        let bytes = std::fs::read(path)?;
+        let mut entries = vec![];
+        let mut decoder = Decoder::new(&bytes);
+        let mut valid_len = 0;
+        while !decoder.is_empty() {
+            let Ok(len) = decoder.u32() else { break };
+            let Ok(crc) = decoder.u32() else { break };
+            let Ok(payload) = decoder.bytes(len as usize) else {
+                break;
+            };
+            if crc32(payload) != crc {
+                break;
+            }
+            entries.push(LogEntry::decode(payload)?);
+            valid_len += 8 + len as u64;
+        }
+        Ok((entries, valid_len))
+    }
+
+    /// Rebuild the segment from `snapshot`, its state when this log was created, ARIES
+    /// style:
+    /// 1. Analysis finds the transactions without commit or abort record, the losers.
+    /// 2. Redo repeats history, every update and CLR is applied in LSN order, those of
+    ///    losers and of rolled back transactions included.
+    /// 3. Undo rolls the losers back, latest record first, logging a CLR for every
+    ///    update undone and an abort record once a loser is done.
+    pub fn recover(&self, snapshot: Segment) -> Result<Recovery> {
+        let entries = self.entries()?;
+        let segment_id = snapshot.segment_id().to_string();
+        let mut segment = snapshot;
+
+        // Analysis: the last LSN of every transaction, minus those that ended.
+        let mut last_lsn = HashMap::new();
+        let mut committed = vec![];
+        for entry in entries.iter() {
+            match &entry.record {
+                LogRecord::Commit => {
+                    last_lsn.remove(&entry.txn_id);
+                    committed.push(entry.txn_id.clone());
+                }
+                LogRecord::Abort => {
+                    last_lsn.remove(&entry.txn_id);
+                }
+                _ => {
+                    last_lsn.insert(entry.txn_id.clone(), entry.lsn);
+                }
+            }
+        }
+
+        // Redo.
+        let mut redone = 0;
+        for entry in entries.iter() {
+            let (entry_segment, index, value) = match &entry.record {
+                LogRecord::Update {
+                    segment_id,
+                    index,
+                    after,
+                    ..
+                } => (segment_id, *index, after),
+                LogRecord::Compensation {
+                    segment_id,
+                    index,
+                    value,
+                    ..
+                } => (segment_id, *index, value),
+                _ => continue,
+            };
+            if entry_segment != &segment_id {
+                return Err(anyhow!(WalErrorCode::ForeignSegment(
+                    entry.lsn,
+                    entry_segment.clone()
+                )));
+            }
+            Self::apply(&mut segment, entry.lsn, index, value)?;
+            redone += 1;
+        }
+
+        // Undo, always the highest LSN left of any loser next.
+        let by_lsn = entries
+            .iter()
+            .map(|entry| (entry.lsn, entry))
+            .collect::<HashMap<_, _>>();
+        let mut losers = last_lsn.keys().cloned().collect::<Vec<_>>();
+        losers.sort();
+        let mut to_undo = last_lsn
+            .iter()
+            .map(|(txn_id, lsn)| (*lsn, txn_id.clone()))
+            .collect::<BTreeMap<_, _>>();
+        let mut undone = 0;
+        while let Some((lsn, txn_id)) = to_undo.pop_last() {
+            let entry = by_lsn[&lsn];
+            let undo_next_lsn = match &entry.record {
+                LogRecord::Update {
+                    segment_id,
+                    index,
+                    before,
+                    ..
+                } => {
+                    Self::apply(&mut segment, lsn, *index, before)?;
+                    let clr_lsn = self.append(
+                        &txn_id,
+                        last_lsn[&txn_id],
+                        LogRecord::Compensation {
+                            segment_id: segment_id.clone(),
+                            index: *index,
+                            value: before.clone(),
+                            undo_next_lsn: entry.prev_lsn,
+                        },
+                    )?;
+                    last_lsn.insert(txn_id.clone(), clr_lsn);
+                    undone += 1;
+                    entry.prev_lsn
+                }
+                LogRecord::Compensation { undo_next_lsn, .. } => *undo_next_lsn,
+                _ => entry.prev_lsn,
+            };
+            if undo_next_lsn == 0 {
+                self.append(&txn_id, last_lsn[&txn_id], LogRecord::Abort)?;
+            } else {
+                to_undo.insert(undo_next_lsn, txn_id);
+            }
+        }
+        self.flush()?;
+        Ok(Recovery {
+            segment,
+            redone,
+            undone,
+            committed,
+            losers,
+        })
+    }
+
+    fn apply(segment: &mut Segment, lsn: Lsn, index: usize, value: &Value) -> Result<()> {
+        segment
+            .update_value(index, value.clone())
+            .map_err(|_| anyhow!(WalErrorCode::IndexOutOfRange(lsn, index)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lock_mgr::LockTable;
+    use crate::segment::Segment;
+    use crate::segment_store::SegmentStore;
+    use crate::transaction::Transaction;
+    use crate::wal::{LogEntry, LogRecord, Wal};
+    use std::io::Write;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    fn wal_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("r_tpl_{}_{}.wal", name, std::process::id()))
+    }
+
+    fn snapshot(name: &str) -> Segment {
+        let ints = (0..30).collect::<Vec<_>>();
+        Segment::from_ints(10, &ints, name.to_string())
+    }
+
+    #[test]
+    pub fn test_torn_tail() {
+        let path = wal_path("torn");
+        let wal = Wal::create(&path).unwrap();
+        let begin = wal.append("torn_1", 0, LogRecord::Begin).unwrap();
+        let update = LogRecord::Update {
+            segment_id: "torn_seg".to_string(),
+            index: 3,
+            before: 3.into(),
+            after: (-3).into(),
+        };
+        let update_lsn = wal.append("torn_1", begin, update.clone()).unwrap();
+        wal.flush().unwrap();
+        drop(wal);
+        // A crash in the middle of writing the next record.
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(&[40, 0, 0, 0, 1, 2])
+            .unwrap();
+
+        let wal = Wal::open(&path).unwrap();
+        let commit = wal.append("torn_1", update_lsn, LogRecord::Commit).unwrap();
+        assert_eq!(3, commit);
+        assert_eq!(
+            vec![
+                LogEntry {
+                    lsn: 2,
+                    txn_id: "torn_1".to_string(),
+                    prev_lsn: 1,
+                    record: update,
+                },
+                LogEntry {
+                    lsn: 3,
+                    txn_id: "torn_1".to_string(),
+                    prev_lsn: 2,
+                    record: LogRecord::Commit,
+                }
+            ],
+            wal.entries().unwrap()[1..]
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    pub fn test_recover_after_crash() {
+        let path = wal_path("crash");
+        let snapshot_path = path.with_extension("seg");
+        let snapshot = snapshot("wal_crash");
+        snapshot.save(&snapshot_path).unwrap();
+        {
+            let lock_table = Arc::new(LockTable::new());
+            let wal = Arc::new(Wal::create(&path).unwrap());
+            let store = SegmentStore::new(snapshot.clone()).with_wal(wal);
+            let mut committed = Transaction::new(lock_table.clone(), "crash_c".to_string());
+            store.write(&mut committed, 1, 100).unwrap();
+            store.write(&mut committed, 2, 200).unwrap();
+            committed.commit().unwrap();
+            let mut aborted = Transaction::new(lock_table.clone(), "crash_a".to_string());
+            store.write(&mut aborted, 1, 101).unwrap();
+            store.write(&mut aborted, 11, 1100).unwrap();
+            aborted.abort().unwrap();
+            let mut loser = Transaction::new(lock_table.clone(), "crash_l".to_string());
+            store.write(&mut loser, 2, 202).unwrap();
+            store.write(&mut loser, 21, 2100).unwrap();
+            store.write(&mut loser, 21, 2101).unwrap();
+            // Crash: the loser neither commits nor aborts, only the log survives.
+        }
+        // Restart from the base image on disk.
+        let snapshot = Segment::load(&snapshot_path).unwrap();
+
+        let mut expected = snapshot.clone();
+        expected.update_value(1, 100).unwrap();
+        expected.update_value(2, 200).unwrap();
+        let wal = Wal::open(&path).unwrap();
+        let recovery = wal.recover(snapshot.clone()).unwrap();
+        assert_eq!(expected, recovery.segment);
+        assert_eq!(vec!["crash_c"], recovery.committed);
+        assert_eq!(vec!["crash_l"], recovery.losers);
+        assert_eq!(3, recovery.undone);
+        assert_eq!(2 + 4 + 3, recovery.redone);
+
+        // Recovering again repeats the compensations logged by the first recovery.
+        let recovery = wal.recover(snapshot).unwrap();
+        assert_eq!(expected, recovery.segment);
+        assert!(recovery.losers.is_empty());
+        assert_eq!(0, recovery.undone);
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(snapshot_path).unwrap();
+    }
+
+    #[test]
+    pub fn test_recover_interrupted_rollback() {
+        let path = wal_path("interrupted");
+        let segment = snapshot("wal_int");
+        let wal = Wal::create(&path).unwrap();
+        let update = |index: usize, after: i32| LogRecord::Update {
+            segment_id: "wal_int".to_string(),
+            index,
+            before: (index as i32).into(),
+            after: after.into(),
+        };
+        let begin = wal.append("int_1", 0, LogRecord::Begin).unwrap();
+        let first = wal.append("int_1", begin, update(4, 40)).unwrap();
+        let second = wal.append("int_1", first, update(5, 50)).unwrap();
+        // The rollback undid the second update, then the system crashed.
+        wal.append(
+            "int_1",
+            second,
+            LogRecord::Compensation {
+                segment_id: "wal_int".to_string(),
+                index: 5,
+                value: 5.into(),
+                undo_next_lsn: first,
+            },
+        )
+        .unwrap();
+        let other = wal.append("int_2", 0, LogRecord::Begin).unwrap();
+        let other_update = wal.append("int_2", other, update(6, 60)).unwrap();
+        wal.append("int_2", other_update, LogRecord::Commit)
+            .unwrap();
+
+        let recovery = wal.recover(segment).unwrap();
+        assert_eq!(1, recovery.undone);
+        assert_eq!(4, recovery.segment.get_value(4).unwrap());
+        assert_eq!(5, recovery.segment.get_value(5).unwrap());
+        assert_eq!(60, recovery.segment.get_value(6).unwrap());
+        let entries = wal.entries().unwrap();
+        assert!(matches!(
+            &entries[entries.len() - 2].record,
+            LogRecord::Compensation { index: 4, value, undo_next_lsn, .. }
+                if *value == 4 && *undo_next_lsn == begin
+        ));
+        assert_eq!(LogRecord::Abort, entries[entries.len() - 1].record);
+
+        // Updates of another segment do not belong in this log.
+        assert!(wal.recover(snapshot("wal_other")).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}